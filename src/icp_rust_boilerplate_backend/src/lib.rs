@@ -1,35 +1,234 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{
+    BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, StableLog, Storable,
+};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::time::Duration;
 use std::{borrow::Cow, cell::RefCell};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-enum Category {
+// Version of the on-disk record layout. Bump it whenever a stored record
+// changes shape and add a migration from the previous layout.
+const SCHEMA_VERSION: u8 = 8;
+
+// Maximum number of products returned by a single list_products call
+const MAX_PAGE_SIZE: u32 = 100;
+
+// Maximum number of line items in a single order
+const MAX_ORDER_LINES: usize = 50;
+
+// Longest a reservation may hold stock before it expires
+const MAX_RESERVATION_TTL_SECONDS: u64 = 24 * 60 * 60;
+
+// Maximum number of ingredients in a single recipe
+const MAX_RECIPE_LINES: usize = 30;
+
+// Maximum length of a batch lot code
+const MAX_LOT_CODE_LENGTH: usize = 64;
+
+// Maximum number of products and ingredients a single supplier delivers
+const MAX_SUPPLIED_ITEMS: usize = 100;
+
+// Maximum length of a supplier's name
+const MAX_SUPPLIER_NAME_LENGTH: usize = 128;
+
+// Maximum length of a supplier's contact details
+const MAX_CONTACT_LENGTH: usize = 256;
+
+// Longest lead time a supplier may have, in days
+const MAX_LEAD_TIME_DAYS: u32 = 365;
+
+// Number of audit entries kept, the oldest are dropped first
+const MAX_AUDIT_ENTRIES: u64 = 10_000;
+
+// Longest before/after summary stored in an audit entry, in bytes
+const MAX_AUDIT_SUMMARY_LENGTH: usize = 1024;
+
+// Appended to an audit summary that was cut short
+const AUDIT_TRUNCATED_MARKER: &str = "...[truncated]";
+
+// Update endpoints that may be called on an empty canister before a restore.
+// Their audit entries do not count as data and are kept through the restore.
+const RESTORE_SETUP_METHODS: [&str; 8] = [
+    "grant_role",
+    "revoke_role",
+    "set_auto_reorder",
+    "set_idempotency_retention",
+    "set_product_history_retention",
+    "create_backup",
+    "start_restore",
+    "upload_restore_chunk",
+];
+
+// Names of the sections of a backup snapshot, one per stable structure. Must
+// match the sections written by take_snapshot.
+const SNAPSHOT_SECTIONS: [&str; 35] = [
+    "schema_version",
+    "product_id_counter",
+    "products",
+    "category_index",
+    "roles",
+    "movements",
+    "product_movements",
+    "price_history",
+    "order_id_counter",
+    "orders",
+    "reservation_id_counter",
+    "reservations",
+    "product_reservations",
+    "batch_id_counter",
+    "batches",
+    "waste_id_counter",
+    "waste",
+    "last_sweep",
+    "ingredient_id_counter",
+    "ingredients",
+    "recipes",
+    "supplier_id_counter",
+    "suppliers",
+    "purchase_order_id_counter",
+    "purchase_orders",
+    "reorder_settings",
+    "category_id_counter",
+    "categories",
+    "search_index",
+    "idempotency_settings",
+    "audit_id_counter",
+    "audit_log",
+    "product_history",
+    "product_history_times",
+    "history_settings",
+];
+
+// Largest chunk of a backup snapshot sent or received in one call
+const MAX_BACKUP_CHUNK_SIZE: usize = 1_000_000;
+
+// Largest backup snapshot. A snapshot is encoded by create_backup and decoded and
+// applied by finish_restore within a single message, so it must stay well within
+// the instruction and heap limits of one call.
+const MAX_BACKUP_SIZE: usize = 32_000_000;
+
+// Maximum number of rows in a single bulk update
+const MAX_BULK_ROWS: usize = 100;
+
+// Maximum number of records in a single CSV export chunk
+const MAX_CSV_CHUNK_ROWS: u32 = 500;
+
+// Maximum number of rows in a single CSV import
+const MAX_CSV_IMPORT_ROWS: usize = 500;
+
+// Columns of the product CSV export and import
+const PRODUCT_CSV_COLUMNS: [&str; 14] = [
+    "id",
+    "name",
+    "category_id",
+    "quantity",
+    "unit",
+    "price",
+    "unit_cost",
+    "currency",
+    "reorder_point",
+    "reorder_quantity",
+    "created_at",
+    "updated_at",
+    "archived_at",
+    "version",
+];
+
+// Columns of the stock movement CSV export
+const MOVEMENT_CSV_COLUMNS: [&str; 8] = [
+    "id",
+    "product_id",
+    "delta",
+    "reason",
+    "caller",
+    "timestamp",
+    "resulting_quantity",
+    "supplier_id",
+];
+
+// Maximum number of line items in a single purchase order
+const MAX_PURCHASE_ORDER_LINES: usize = 50;
+
+// How often expired reservations are released
+const RESERVATION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often batches past their best before date are written off
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How often purchase orders are drafted for low stock, when enabled
+const REORDER_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Maximum length of a category name
+const MAX_CATEGORY_NAME_LENGTH: usize = 64;
+
+// Maximum length of a product name, in bytes, so a product fits in Product::MAX_SIZE
+const MAX_PRODUCT_NAME_LENGTH: usize = 256;
+
+// Maximum length of an ingredient name, in bytes, so an ingredient fits in
+// Ingredient::MAX_SIZE
+const MAX_INGREDIENT_NAME_LENGTH: usize = 128;
+
+// Maximum length of a client-supplied idempotency key
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 64;
+
+// How long idempotency keys are remembered unless configured otherwise
+const DEFAULT_IDEMPOTENCY_RETENTION_SECONDS: u64 = 24 * 60 * 60;
+
+// Longest idempotency keys may be remembered
+const MAX_IDEMPOTENCY_RETENTION_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+// How often idempotency keys past their retention window are forgotten
+const IDEMPOTENCY_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Longest search token kept in the search index, in bytes. Longer words are
+// indexed by their first MAX_TOKEN_LENGTH bytes.
+const MAX_TOKEN_LENGTH: usize = 32;
+
+// Fixed categories of schema versions 1 to 4, replaced by stored categories
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq)]
+enum LegacyCategory {
     #[default]
     Bakery,
     Cake,
     Cookies,
 }
 
+// Id of the seeded category that replaces a legacy category
+fn legacy_category_id(category: &LegacyCategory) -> u64 {
+    match category {
+        LegacyCategory::Bakery => 0,
+        LegacyCategory::Cake => 1,
+        LegacyCategory::Cookies => 2,
+    }
+}
+
+// Categories seeded in place of the legacy ones, in id order
+const SEEDED_CATEGORIES: [&str; 3] = ["Bakery", "Cake", "Cookies"];
+
+// A product category. Categories form a hierarchy through their parent.
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct Product {
+struct Category {
     id: u64,
     name: String,
-    category: Category,
-    quantity: u32,
+    parent_id: Option<u64>,
+    // Position among the categories sharing the same parent, lowest first
+    display_order: u32,
     created_at: u64,
     updated_at: Option<u64>,
 }
 
-// Implementing Storable for Product to convert to/from bytes for storage
-impl Storable for Product {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+// Implementing Storable for Category to convert to/from bytes for storage
+impl Storable for Category {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -38,217 +237,7427 @@ impl Storable for Product {
     }
 }
 
-// Implementing BoundedStorable to define size limitations for Product storage
-impl BoundedStorable for Product {
-    const MAX_SIZE: u32 = 1024; // Maximum size for a Product in bytes
+// Implementing BoundedStorable to define size limitations for Category storage
+impl BoundedStorable for Category {
+    const MAX_SIZE: u32 = 256;
     const IS_FIXED_SIZE: bool = false;
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+// Units of measure. Quantities are stored in the base unit of their
+// dimension: pieces, grams or millilitres.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Debug)]
+enum Unit {
+    #[default]
+    Piece,
+    Dozen,
+    Gram,
+    Kilogram,
+    Millilitre,
+    Litre,
+}
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+// What a unit measures; only units of the same dimension convert into each other
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Dimension {
+    Count,
+    Mass,
+    Volume,
+}
 
-    static STORAGE: RefCell<StableBTreeMap<u64, Product, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+impl Unit {
+    fn dimension(self) -> Dimension {
+        match self {
+            Unit::Piece | Unit::Dozen => Dimension::Count,
+            Unit::Gram | Unit::Kilogram => Dimension::Mass,
+            Unit::Millilitre | Unit::Litre => Dimension::Volume,
+        }
+    }
+
+    // The unit quantities of this unit's dimension are stored in
+    fn base(self) -> Unit {
+        match self.dimension() {
+            Dimension::Count => Unit::Piece,
+            Dimension::Mass => Unit::Gram,
+            Dimension::Volume => Unit::Millilitre,
+        }
+    }
+
+    // Number of base units in one of this unit
+    fn factor(self) -> u64 {
+        match self {
+            Unit::Piece | Unit::Gram | Unit::Millilitre => 1,
+            Unit::Dozen => 12,
+            Unit::Kilogram | Unit::Litre => 1000,
+        }
+    }
+
+    fn symbol(self) -> &'static str {
+        match self {
+            Unit::Piece => "pc",
+            Unit::Dozen => "dozen",
+            Unit::Gram => "g",
+            Unit::Kilogram => "kg",
+            Unit::Millilitre => "ml",
+            Unit::Litre => "l",
+        }
+    }
+
+    // Unit written as its symbol or its name, ignoring case
+    fn parse(text: &str) -> Option<Unit> {
+        let text = text.trim().to_lowercase();
+        [
+            Unit::Piece,
+            Unit::Dozen,
+            Unit::Gram,
+            Unit::Kilogram,
+            Unit::Millilitre,
+            Unit::Litre,
+        ]
+        .into_iter()
+        .find(|unit| unit.symbol() == text || format!("{:?}", unit).to_lowercase() == text)
+    }
 }
 
-// Product payload struct used to create or update a product
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct ProductPayload {
+// Function to convert an amount given in `from` into the base unit of `to`
+fn to_base_quantity(amount: u64, from: Unit, to: Unit) -> Result<u64, Error> {
+    if from.dimension() != to.dimension() {
+        return Err(Error::IncompatibleUnit {
+            msg: format!(
+                "Cannot convert {:?} into {:?}: {:?} and {:?} are different dimensions",
+                from,
+                to,
+                from.dimension(),
+                to.dimension()
+            ),
+        });
+    }
+    amount
+        .checked_mul(from.factor())
+        .ok_or(Error::InvalidOperation {
+            msg: format!("Amount {} {} is too large", amount, from.symbol()),
+        })
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Product {
+    id: u64,
     name: String,
+    category_id: u64,
+    // Stock on hand, in the base unit `unit`
     quantity: u32,
-    category: Category,
+    unit: Unit,
+    // Sale price and unit cost in the smallest unit of `currency` (e.g. cents)
+    price: u64,
+    unit_cost: u64,
+    currency: String,
+    // Stock level at or below which the product needs restocking, if tracked
+    reorder_point: Option<u32>,
+    // Quantity to order when restocking
+    reorder_quantity: u32,
+    created_at: u64,
+    updated_at: Option<u64>,
+    // Archived products are hidden from listings and take no stock changes
+    archived_at: Option<u64>,
+    // Incremented on every write, for optimistic concurrency control
+    version: u64,
 }
 
-// Payload for adding or removing stock
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct StockPayload {
-    amount: u32,
+// Product layout of schema version 1, before prices were added
+#[derive(candid::CandidType, Deserialize)]
+struct ProductV1 {
+    id: u64,
+    name: String,
+    category: LegacyCategory,
+    quantity: u32,
+    created_at: u64,
+    updated_at: Option<u64>,
 }
 
-// Function to validate ProductPayload inputs
-fn validate_product_payload(payload: &ProductPayload) -> Result<(), Error> {
-    if payload.name.trim().is_empty() {
-        return Err(Error::InvalidOperation {
-            msg: "Product name cannot be empty.".to_string(),
-        });
-    }
-    if payload.quantity == 0 {
-        return Err(Error::InvalidOperation {
-            msg: "Product quantity must be greater than zero.".to_string(),
-        });
-    }
-    Ok(())
+// Product layout of schema version 2, before units of measure were added
+#[derive(candid::CandidType, Deserialize)]
+struct ProductV2 {
+    id: u64,
+    name: String,
+    category: LegacyCategory,
+    quantity: u32,
+    price: u64,
+    unit_cost: u64,
+    currency: String,
+    created_at: u64,
+    updated_at: Option<u64>,
 }
 
-// Function to validate StockPayload inputs
-fn validate_stock_payload(payload: &StockPayload) -> Result<(), Error> {
-    if payload.amount == 0 {
-        return Err(Error::InvalidOperation {
-            msg: "Stock amount must be greater than zero.".to_string(),
-        });
+// Migration from version 1: products without a price start out free, in USD
+impl From<ProductV1> for ProductV2 {
+    fn from(product: ProductV1) -> Self {
+        ProductV2 {
+            id: product.id,
+            name: product.name,
+            category: product.category,
+            quantity: product.quantity,
+            price: 0,
+            unit_cost: 0,
+            currency: "USD".to_string(),
+            created_at: product.created_at,
+            updated_at: product.updated_at,
+        }
     }
-    Ok(())
 }
 
-// Helper function to retrieve a product by its ID
-fn _get_product(id: &u64) -> Option<Product> {
-    STORAGE.with(|service| service.borrow().get(id))
+// Product layout of schema version 3, before reorder points were added
+#[derive(candid::CandidType, Deserialize)]
+struct ProductV3 {
+    id: u64,
+    name: String,
+    category: LegacyCategory,
+    quantity: u32,
+    unit: Unit,
+    price: u64,
+    unit_cost: u64,
+    currency: String,
+    created_at: u64,
+    updated_at: Option<u64>,
 }
 
-// Query function to retrieve a product by ID
-#[ic_cdk::query]
-fn get_product(id: u64) -> Result<Product, Error> {
-    match _get_product(&id) {
-        Some(product) => Ok(product),
-        None => Err(Error::NotFound {
-            msg: format!("A product with id={} was not found", id),
-        }),
+// Migration from version 2: existing products were counted in pieces
+impl From<ProductV2> for ProductV3 {
+    fn from(product: ProductV2) -> Self {
+        ProductV3 {
+            id: product.id,
+            name: product.name,
+            category: product.category,
+            quantity: product.quantity,
+            unit: Unit::Piece,
+            price: product.price,
+            unit_cost: product.unit_cost,
+            currency: product.currency,
+            created_at: product.created_at,
+            updated_at: product.updated_at,
+        }
     }
 }
 
-// Query function to get the current stock of a product by ID
-#[ic_cdk::query]
-fn get_stock(id: u64) -> Result<u32, Error> {
-    match _get_product(&id) {
-        Some(product) => Ok(product.quantity),
-        None => Err(Error::NotFound {
-            msg: format!("A product with id={} was not found", id),
-        }),
-    }
+// Product layout of schema version 4, before categories were stored as data
+#[derive(candid::CandidType, Deserialize)]
+struct ProductV4 {
+    id: u64,
+    name: String,
+    category: LegacyCategory,
+    quantity: u32,
+    unit: Unit,
+    price: u64,
+    unit_cost: u64,
+    currency: String,
+    reorder_point: Option<u32>,
+    reorder_quantity: u32,
+    created_at: u64,
+    updated_at: Option<u64>,
 }
 
-// Function to insert a product into the stable storage
-fn do_insert(product: &Product) {
-    STORAGE.with(|service| service.borrow_mut().insert(product.id, product.clone()));
+// Migration from version 3: existing products are not tracked for reordering
+impl From<ProductV3> for ProductV4 {
+    fn from(product: ProductV3) -> Self {
+        ProductV4 {
+            id: product.id,
+            name: product.name,
+            category: product.category,
+            quantity: product.quantity,
+            unit: product.unit,
+            price: product.price,
+            unit_cost: product.unit_cost,
+            currency: product.currency,
+            reorder_point: None,
+            reorder_quantity: 0,
+            created_at: product.created_at,
+            updated_at: product.updated_at,
+        }
+    }
 }
 
-// Function to add a new product to the storage
-#[ic_cdk::update]
-fn add_product(product: ProductPayload) -> Result<Product, Error> {
-    // Validate payload before processing
-    validate_product_payload(&product)?;
+// Product layout of schema version 5, before products could be archived
+#[derive(candid::CandidType, Deserialize)]
+struct ProductV5 {
+    id: u64,
+    name: String,
+    category_id: u64,
+    quantity: u32,
+    unit: Unit,
+    price: u64,
+    unit_cost: u64,
+    currency: String,
+    reorder_point: Option<u32>,
+    reorder_quantity: u32,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
 
-    // Generate a unique ID for the product
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment id counter");
-    
-    // Create a new Product instance
-    let item = Product {
-        id,
-        name: product.name,
-        category: product.category, 
-        quantity: product.quantity,
-        created_at: time(),
-        updated_at: None,
-    };
+// Migration from version 4: products move to the seeded category of their legacy category
+impl From<ProductV4> for ProductV5 {
+    fn from(product: ProductV4) -> Self {
+        ProductV5 {
+            id: product.id,
+            name: product.name,
+            category_id: legacy_category_id(&product.category),
+            quantity: product.quantity,
+            unit: product.unit,
+            price: product.price,
+            unit_cost: product.unit_cost,
+            currency: product.currency,
+            reorder_point: product.reorder_point,
+            reorder_quantity: product.reorder_quantity,
+            created_at: product.created_at,
+            updated_at: product.updated_at,
+        }
+    }
+}
 
-    // Insert the new product into storage
-    do_insert(&item);
-    Ok(item)
+// Product layout of schema version 6, before products were versioned
+#[derive(candid::CandidType, Deserialize)]
+struct ProductV6 {
+    id: u64,
+    name: String,
+    category_id: u64,
+    quantity: u32,
+    unit: Unit,
+    price: u64,
+    unit_cost: u64,
+    currency: String,
+    reorder_point: Option<u32>,
+    reorder_quantity: u32,
+    created_at: u64,
+    updated_at: Option<u64>,
+    archived_at: Option<u64>,
 }
 
-// Function to update an existing product's details
-#[ic_cdk::update]
-fn update_product(id: u64, payload: ProductPayload) -> Result<Product, Error> {
-    // Validate payload before processing
-    validate_product_payload(&payload)?;
+// Migration from version 5: existing products are active
+impl From<ProductV5> for ProductV6 {
+    fn from(product: ProductV5) -> Self {
+        ProductV6 {
+            id: product.id,
+            name: product.name,
+            category_id: product.category_id,
+            quantity: product.quantity,
+            unit: product.unit,
+            price: product.price,
+            unit_cost: product.unit_cost,
+            currency: product.currency,
+            reorder_point: product.reorder_point,
+            reorder_quantity: product.reorder_quantity,
+            created_at: product.created_at,
+            updated_at: product.updated_at,
+            archived_at: None,
+        }
+    }
+}
 
-    // Update the product if it exists in storage
-    match STORAGE.with(|service| service.borrow().get(&id)) {
-        Some(mut product) => {
-            product.name = payload.name;
-            product.category = payload.category;
-            product.quantity = payload.quantity;
-            product.updated_at = Some(time());
-            do_insert(&product);
-            Ok(product)
+// Migration from version 6: existing products start at version 1
+impl From<ProductV6> for Product {
+    fn from(product: ProductV6) -> Self {
+        Product {
+            id: product.id,
+            name: product.name,
+            category_id: product.category_id,
+            quantity: product.quantity,
+            unit: product.unit,
+            price: product.price,
+            unit_cost: product.unit_cost,
+            currency: product.currency,
+            reorder_point: product.reorder_point,
+            reorder_quantity: product.reorder_quantity,
+            created_at: product.created_at,
+            updated_at: product.updated_at,
+            archived_at: product.archived_at,
+            version: 1,
         }
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't update a product with id={}. Product not found", id),
-        }),
     }
 }
 
-// Function to add stock to a product's quantity
-#[ic_cdk::update]
-fn add_quantity(id: u64, payload: StockPayload) -> Result<Product, Error> {
-    // Validate the stock payload
-    validate_stock_payload(&payload)?;
+// Function to decode a product stored with the given schema version
+fn decode_product(version: u8, bytes: &[u8]) -> Result<Product, String> {
+    match version {
+        1 => Decode!(bytes, ProductV1)
+            .map(ProductV2::from)
+            .map(ProductV3::from)
+            .map(ProductV4::from)
+            .map(ProductV5::from)
+            .map(ProductV6::from)
+            .map(Product::from)
+            .map_err(|e| e.to_string()),
+        2 => Decode!(bytes, ProductV2)
+            .map(ProductV3::from)
+            .map(ProductV4::from)
+            .map(ProductV5::from)
+            .map(ProductV6::from)
+            .map(Product::from)
+            .map_err(|e| e.to_string()),
+        3 => Decode!(bytes, ProductV3)
+            .map(ProductV4::from)
+            .map(ProductV5::from)
+            .map(ProductV6::from)
+            .map(Product::from)
+            .map_err(|e| e.to_string()),
+        4 => Decode!(bytes, ProductV4)
+            .map(ProductV5::from)
+            .map(ProductV6::from)
+            .map(Product::from)
+            .map_err(|e| e.to_string()),
+        5 => Decode!(bytes, ProductV5)
+            .map(ProductV6::from)
+            .map(Product::from)
+            .map_err(|e| e.to_string()),
+        6 => Decode!(bytes, ProductV6)
+            .map(Product::from)
+            .map_err(|e| e.to_string()),
+        // The product layout did not change after version 7
+        7..=8 => Decode!(bytes, Product).map_err(|e| e.to_string()),
+        other => Err(format!("unknown product schema version {}", other)),
+    }
+}
 
-    match STORAGE.with(|service| service.borrow().get(&id)) {
-        Some(mut product) => {
-            product.quantity += payload.amount;
-            product.updated_at = Some(time());
-            do_insert(&product);
-            Ok(product)
-        }
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't add quantity to product with id={}. Product not found", id),
-        }),
+// Implementing Storable for Product to convert to/from bytes for storage.
+// Records are stored as a schema version byte followed by the Candid encoding.
+// Records written before versioning are bare Candid, starting with "DIDL".
+impl Storable for Product {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = vec![SCHEMA_VERSION];
+        bytes.extend(Encode!(self).unwrap());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let decoded = if bytes.starts_with(b"DIDL") {
+            // Unversioned records have either the version 2 or the version 1 layout
+            decode_product(2, &bytes).or_else(|_| decode_product(1, &bytes))
+        } else {
+            decode_product(bytes[0], &bytes[1..])
+        };
+        decoded.unwrap_or_else(|e| ic_cdk::trap(&format!("Cannot decode product: {}", e)))
     }
 }
 
-// Function to remove stock from a product's quantity
-#[ic_cdk::update]
-fn offload_quantity(id: u64, payload: StockPayload) -> Result<Product, Error> {
-    // Validate the stock payload
-    validate_stock_payload(&payload)?;
+// Implementing BoundedStorable to define size limitations for Product storage
+impl BoundedStorable for Product {
+    const MAX_SIZE: u32 = 1024; // Maximum size for a Product in bytes
+    const IS_FIXED_SIZE: bool = false;
+}
 
-    match STORAGE.with(|service| service.borrow().get(&id)) {
-        Some(mut product) => {
-            if product.quantity == 0 {
-                return Err(Error::InvalidOperation {
-                    msg: format!("Product with id={} cannot be offloaded because the quantity is 0", id),
-                });
-            } else if payload.amount > product.quantity {
-                return Err(Error::InvalidOperation {
-                    msg: format!(
-                        "Cannot offload more than available quantity. Available: {}, Trying to offload: {}",
-                        product.quantity, payload.amount
-                    ),
-                });
-            }
-            product.quantity -= payload.amount;
-            product.updated_at = Some(time());
-            do_insert(&product);
-            Ok(product)
+// A past or current version of a product, along with when it was written
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ProductRevision {
+    recorded_at: u64,
+    product: Product,
+}
+
+// Implementing Storable for ProductRevision. The timestamp is stored as 8 bytes
+// in front of the product, which keeps its versioned layout.
+impl Storable for ProductRevision {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = self.recorded_at.to_le_bytes().to_vec();
+        bytes.extend(self.product.to_bytes().iter());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (recorded_at, product) = bytes.split_at(8);
+        ProductRevision {
+            recorded_at: u64::from_le_bytes(recorded_at.try_into().unwrap()),
+            product: Product::from_bytes(Cow::Borrowed(product)),
         }
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't offload a product with id={}. Product not found", id),
-        }),
     }
 }
 
-// Function to remove a product from storage
-#[ic_cdk::update]
-fn remove_product(id: u64) -> Result<Product, Error> {
-    match STORAGE.with(|service| service.borrow_mut().remove(&id)) {
-        Some(product) => Ok(product),
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't delete a product with id={}. Product not found", id),
-        }),
-    }
+// Implementing BoundedStorable to define size limitations for ProductRevision storage
+impl BoundedStorable for ProductRevision {
+    const MAX_SIZE: u32 = <Product as BoundedStorable>::MAX_SIZE + 8;
+    const IS_FIXED_SIZE: bool = false;
 }
 
-// Custom error handling enum
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
-    InvalidOperation { msg: String },
+// A lowercase word of a product name, used as the first half of SEARCH_INDEX keys
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct SearchToken(String);
+
+// Implementing Storable for SearchToken, stored as its UTF-8 bytes
+impl Storable for SearchToken {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        SearchToken(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for SearchToken storage
+impl BoundedStorable for SearchToken {
+    const MAX_SIZE: u32 = MAX_TOKEN_LENGTH as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A client-supplied idempotency key, used as the second half of IDEMPOTENCY keys
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct IdempotencyKey(String);
+
+// Implementing Storable for IdempotencyKey, stored as its UTF-8 bytes
+impl Storable for IdempotencyKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        IdempotencyKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for IdempotencyKey storage
+impl BoundedStorable for IdempotencyKey {
+    const MAX_SIZE: u32 = MAX_IDEMPOTENCY_KEY_LENGTH as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Successful result of an update call, kept to answer retries with the same key
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IdempotencyRecord {
+    endpoint: String,
+    // Candid encoding of the call's result
+    result: Vec<u8>,
+    created_at: u64,
+}
+
+// Implementing Storable for IdempotencyRecord to convert to/from bytes for storage
+impl Storable for IdempotencyRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for IdempotencyRecord storage
+impl BoundedStorable for IdempotencyRecord {
+    const MAX_SIZE: u32 = 8192; // Enough for an Order or the product versions of a full CSV import
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Settings of the idempotency key store
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IdempotencySettings {
+    retention_seconds: u64,
+}
+
+impl Default for IdempotencySettings {
+    fn default() -> Self {
+        IdempotencySettings {
+            retention_seconds: DEFAULT_IDEMPOTENCY_RETENTION_SECONDS,
+        }
+    }
+}
+
+// Implementing Storable for IdempotencySettings so it can be kept in a stable cell
+impl Storable for IdempotencySettings {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Settings of the product history
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct HistorySettings {
+    // Number of versions kept per product, the oldest are dropped first.
+    // None keeps every version.
+    max_revisions: Option<u64>,
+}
+
+// Implementing Storable for HistorySettings so it can be kept in a stable cell
+impl Storable for HistorySettings {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// The record an audited call changed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum AuditTarget {
+    // Id of a product, order, supplier or other record
+    Id(u64),
+    Principal(Principal),
+}
+
+// Record of a successful call to an update endpoint
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AuditEntry {
+    id: u64,
+    caller: Principal,
+    method: String,
+    // The record the call changed, when there is a single one
+    target: Option<AuditTarget>,
+    // JSON summaries of the record before and the result after the call,
+    // cut at MAX_AUDIT_SUMMARY_LENGTH
+    before: Option<String>,
+    after: String,
+    timestamp: u64,
+}
+
+// Implementing Storable for AuditEntry to convert to/from bytes for storage
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for AuditEntry storage
+impl BoundedStorable for AuditEntry {
+    const MAX_SIZE: u32 = 2560; // Two summaries plus the method name and principal
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Access roles, ordered from least to most privileged
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+enum Role {
+    Clerk,
+    Manager,
+    Owner,
+}
+
+// Roles are stored as a single byte
+impl Storable for Role {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let byte = match self {
+            Role::Clerk => 0u8,
+            Role::Manager => 1,
+            Role::Owner => 2,
+        };
+        Cow::Owned(vec![byte])
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        match bytes[0] {
+            0 => Role::Clerk,
+            1 => Role::Manager,
+            2 => Role::Owner,
+            other => panic!("Unknown role byte {}", other),
+        }
+    }
+}
+
+impl BoundedStorable for Role {
+    const MAX_SIZE: u32 = 1;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Wrapper making Principal usable as a stable map key
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct StorablePrincipal(Principal);
+
+impl Default for StorablePrincipal {
+    fn default() -> Self {
+        StorablePrincipal(Principal::anonymous())
+    }
+}
+
+impl Storable for StorablePrincipal {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        StorablePrincipal(Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+impl BoundedStorable for StorablePrincipal {
+    const MAX_SIZE: u32 = 29; // Maximum length of a principal in bytes
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Why the stock of a product changed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Debug)]
+enum MovementReason {
+    Initial,
+    Restock,
+    Offload,
+    Adjustment,
+    Removal,
+    Sale,
+    OrderCancelled,
+    Expired,
+    Production,
+}
+
+// Immutable record of a single change to a product's stock
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StockMovement {
+    id: u64,
+    product_id: u64,
+    delta: i64,
+    reason: MovementReason,
+    caller: Principal,
+    timestamp: u64,
+    resulting_quantity: u32,
+    // Supplier that delivered the stock, for movements from purchase orders
+    supplier_id: Option<u64>,
+}
+
+// Implementing Storable for StockMovement so it can be appended to the movement log
+impl Storable for StockMovement {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// A price change recorded in the price history of a product
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PriceChange {
+    product_id: u64,
+    price: u64,
+    unit_cost: u64,
+    currency: String,
+    changed_by: Principal,
+    changed_at: u64,
+}
+
+// Implementing Storable for PriceChange to convert to/from bytes for storage
+impl Storable for PriceChange {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for PriceChange storage
+impl BoundedStorable for PriceChange {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Lifecycle of a sales order
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+enum OrderStatus {
+    Pending,
+    Paid,
+    Fulfilled,
+    Cancelled,
+}
+
+// A single line item of an order, priced when the order was placed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderLine {
+    product_id: u64,
+    quantity: u32,
+    unit_price: u64,
+}
+
+// Stock of an order line that could not be put back when the order was cancelled
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UnreturnedStock {
+    product_id: u64,
+    quantity: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Order {
+    id: u64,
+    lines: Vec<OrderLine>,
+    status: OrderStatus,
+    total: u64,
+    currency: String,
+    placed_by: Principal,
+    created_at: u64,
+    updated_at: Option<u64>,
+    // Stock a cancellation could not return, because the product was archived,
+    // purged or would overflow. Optional so orders stored before it still decode.
+    unreturned: Option<Vec<UnreturnedStock>>,
+}
+
+// Implementing Storable for Order to convert to/from bytes for storage
+impl Storable for Order {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for Order storage
+impl BoundedStorable for Order {
+    const MAX_SIZE: u32 = 4096; // Enough for MAX_ORDER_LINES line items
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Stock of a product held for a customer until it is ordered, released or expires
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Reservation {
+    id: u64,
+    product_id: u64,
+    quantity: u32,
+    created_by: Principal,
+    created_at: u64,
+    expires_at: u64,
+}
+
+// Implementing Storable for Reservation to convert to/from bytes for storage
+impl Storable for Reservation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for Reservation storage
+impl BoundedStorable for Reservation {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A batch (lot) of a product's stock baked at the same time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Batch {
+    id: u64,
+    product_id: u64,
+    lot_code: String,
+    quantity: u32,
+    baked_at: u64,
+    best_before: Option<u64>,
+    created_at: u64,
+}
+
+// Implementing Storable for Batch to convert to/from bytes for storage
+impl Storable for Batch {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for Batch storage
+impl BoundedStorable for Batch {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Why stock was written off as waste
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum WasteReason {
+    Expired,
+}
+
+// Stock of a batch that was written off
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct WasteRecord {
+    id: u64,
+    product_id: u64,
+    batch_id: u64,
+    lot_code: String,
+    quantity: u32,
+    reason: WasteReason,
+    recorded_at: u64,
+}
+
+// Implementing Storable for WasteRecord to convert to/from bytes for storage
+impl Storable for WasteRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for WasteRecord storage
+impl BoundedStorable for WasteRecord {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Outcome of a run of the expiry sweep
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SweepResult {
+    ran_at: u64,
+    batches_written_off: u32,
+    quantity_written_off: u64,
+    products_affected: u32,
+}
+
+// Implementing Storable for SweepResult so the last run can be kept in a stable cell
+impl Storable for SweepResult {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Settings of the automatic reordering job
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ReorderSettings {
+    // Whether the timer drafts purchase orders for low stock
+    auto_draft: bool,
+    // Time the timer last drafted purchase orders
+    last_run_at: Option<u64>,
+}
+
+// Implementing Storable for ReorderSettings so it can be kept in a stable cell
+impl Storable for ReorderSettings {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// A raw material used to make products, such as flour or butter
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Ingredient {
+    id: u64,
+    name: String,
+    // Stock on hand, in the base unit `unit`
+    unit: Unit,
+    quantity: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Ingredient layout of schema version 2, when the unit was free text
+#[derive(candid::CandidType, Deserialize)]
+struct IngredientV2 {
+    id: u64,
+    name: String,
+    unit: String,
+    quantity: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Function to map a free text unit of schema version 2 onto a Unit.
+// Unrecognised units are treated as pieces.
+fn parse_legacy_unit(unit: &str) -> Unit {
+    match unit.trim().to_lowercase().as_str() {
+        "dozen" | "dz" => Unit::Dozen,
+        "g" | "gram" | "grams" | "gramme" | "grammes" => Unit::Gram,
+        "kg" | "kilogram" | "kilograms" | "kilo" | "kilos" => Unit::Kilogram,
+        "ml" | "millilitre" | "millilitres" | "milliliter" | "milliliters" => Unit::Millilitre,
+        "l" | "litre" | "litres" | "liter" | "liters" => Unit::Litre,
+        _ => Unit::Piece,
+    }
+}
+
+// Migration from version 2: quantities are converted into the base unit
+impl From<IngredientV2> for Ingredient {
+    fn from(ingredient: IngredientV2) -> Self {
+        let unit = parse_legacy_unit(&ingredient.unit);
+        Ingredient {
+            id: ingredient.id,
+            name: ingredient.name,
+            unit: unit.base(),
+            quantity: ingredient.quantity.saturating_mul(unit.factor()),
+            created_at: ingredient.created_at,
+            updated_at: ingredient.updated_at,
+        }
+    }
+}
+
+// Function to decode an ingredient stored with the given schema version
+fn decode_ingredient(version: u8, bytes: &[u8]) -> Result<Ingredient, String> {
+    match version {
+        2 => Decode!(bytes, IngredientV2)
+            .map(Ingredient::from)
+            .map_err(|e| e.to_string()),
+        // The ingredient layout did not change after version 3
+        3..=8 => Decode!(bytes, Ingredient).map_err(|e| e.to_string()),
+        other => Err(format!("unknown ingredient schema version {}", other)),
+    }
+}
+
+// Raw view of a stored ingredient, used during migrations to read the
+// original layout before it is converted
+struct RawIngredient(Vec<u8>);
+
+impl Storable for RawIngredient {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        RawIngredient(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for RawIngredient {
+    const MAX_SIZE: u32 = <Ingredient as BoundedStorable>::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implementing Storable for Ingredient, using the same versioned layout as Product
+impl Storable for Ingredient {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = vec![SCHEMA_VERSION];
+        bytes.extend(Encode!(self).unwrap());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_ingredient(bytes[0], &bytes[1..])
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("Cannot decode ingredient: {}", e)))
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for Ingredient storage
+impl BoundedStorable for Ingredient {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Quantity of an ingredient, in the ingredient's base unit, needed to make one unit of a product
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RecipeLine {
+    ingredient_id: u64,
+    quantity: u64,
+}
+
+// Bill of materials of a product
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Recipe {
+    product_id: u64,
+    lines: Vec<RecipeLine>,
+    updated_at: u64,
+}
+
+// Implementing Storable for Recipe to convert to/from bytes for storage
+impl Storable for Recipe {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for Recipe storage
+impl BoundedStorable for Recipe {
+    const MAX_SIZE: u32 = 1024; // Enough for MAX_RECIPE_LINES lines
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A product or ingredient that can be bought from a supplier
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+enum SuppliedItem {
+    Product(u64),
+    Ingredient(u64),
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Supplier {
+    id: u64,
+    name: String,
+    contact: String,
+    // Days between sending a purchase order and its delivery
+    lead_time_days: u32,
+    items: Vec<SuppliedItem>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+// Implementing Storable for Supplier to convert to/from bytes for storage
+impl Storable for Supplier {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for Supplier storage
+impl BoundedStorable for Supplier {
+    const MAX_SIZE: u32 = 4096; // Enough for MAX_SUPPLIED_ITEMS items and the longest name and contact
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Lifecycle of a purchase order
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+enum PurchaseOrderStatus {
+    Draft,
+    Sent,
+    PartiallyReceived,
+    Received,
+    Cancelled,
+}
+
+// A single line of a purchase order. Quantities are in the item's base unit.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PurchaseOrderLine {
+    item: SuppliedItem,
+    quantity: u64,
+    received: u64,
+    unit_cost: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PurchaseOrder {
+    id: u64,
+    supplier_id: u64,
+    lines: Vec<PurchaseOrderLine>,
+    status: PurchaseOrderStatus,
+    created_by: Principal,
+    created_at: u64,
+    // Set when the order is sent, from the supplier's lead time
+    expected_at: Option<u64>,
+    updated_at: Option<u64>,
+}
+
+// Implementing Storable for PurchaseOrder to convert to/from bytes for storage
+impl Storable for PurchaseOrder {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implementing BoundedStorable to define size limitations for PurchaseOrder storage
+impl BoundedStorable for PurchaseOrder {
+    const MAX_SIZE: u32 = 4096; // Enough for MAX_PURCHASE_ORDER_LINES lines
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static STORAGE: RefCell<StableBTreeMap<u64, Product, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    // Secondary index of products keyed by (category id, product id)
+    static CATEGORY_INDEX: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+
+    static ROLES: RefCell<StableBTreeMap<StorablePrincipal, Role, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    // Append-only log of stock movements, indexed by movement id
+    static MOVEMENTS: RefCell<StableLog<StockMovement, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+        )
+        .expect("Cannot create the movement log")
+    );
+
+    // Index of movements keyed by (product id, movement id)
+    static PRODUCT_MOVEMENTS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    // Price history keyed by (product id, time of the change)
+    static PRICE_HISTORY: RefCell<StableBTreeMap<(u64, u64), PriceChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    static ORDER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))), 0)
+            .expect("Cannot create an order counter")
+    );
+
+    static ORDERS: RefCell<StableBTreeMap<u64, Order, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    static RESERVATION_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))), 0)
+            .expect("Cannot create a reservation counter")
+    );
+
+    static RESERVATIONS: RefCell<StableBTreeMap<u64, Reservation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    // Index of reservations keyed by (product id, reservation id)
+    static PRODUCT_RESERVATIONS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    static BATCH_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))), 0)
+            .expect("Cannot create a batch counter")
+    );
+
+    // Batches keyed by (product id, batch id). The quantities of a product's
+    // batches add up to the product's quantity.
+    static BATCHES: RefCell<StableBTreeMap<(u64, u64), Batch, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    static WASTE_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))), 0)
+            .expect("Cannot create a waste counter")
+    );
+
+    static WASTE: RefCell<StableBTreeMap<u64, WasteRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    static LAST_SWEEP: RefCell<Cell<SweepResult, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+            SweepResult::default(),
+        )
+        .expect("Cannot create the last sweep cell")
+    );
+
+    // Schema version the stored records were last migrated to. Zero means the
+    // canister predates schema versioning.
+    static STORED_SCHEMA_VERSION: RefCell<Cell<u8, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))), 0)
+            .expect("Cannot create the schema version cell")
+    );
+
+    static INGREDIENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))), 0)
+            .expect("Cannot create an ingredient counter")
+    );
+
+    static INGREDIENTS: RefCell<StableBTreeMap<u64, Ingredient, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    // Recipes keyed by the id of the product they make
+    static RECIPES: RefCell<StableBTreeMap<u64, Recipe, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    static SUPPLIER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))), 0)
+            .expect("Cannot create a supplier counter")
+    );
+
+    static SUPPLIERS: RefCell<StableBTreeMap<u64, Supplier, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    static PURCHASE_ORDER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))), 0)
+            .expect("Cannot create a purchase order counter")
+    );
+
+    static PURCHASE_ORDERS: RefCell<StableBTreeMap<u64, PurchaseOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    static REORDER_SETTINGS: RefCell<Cell<ReorderSettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+            ReorderSettings::default(),
+        )
+        .expect("Cannot create the reorder settings cell")
+    );
+
+    static CATEGORY_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))), 0)
+            .expect("Cannot create a category counter")
+    );
+
+    static CATEGORIES: RefCell<StableBTreeMap<u64, Category, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // Inverted index of product names keyed by (token, product id)
+    static SEARCH_INDEX: RefCell<StableBTreeMap<(SearchToken, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    // Results of update calls keyed by (caller, idempotency key)
+    static IDEMPOTENCY: RefCell<StableBTreeMap<(StorablePrincipal, IdempotencyKey), IdempotencyRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    static IDEMPOTENCY_SETTINGS: RefCell<Cell<IdempotencySettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+            IdempotencySettings::default(),
+        )
+        .expect("Cannot create the idempotency settings cell")
+    );
+
+    static AUDIT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))), 0)
+            .expect("Cannot create an audit id counter")
+    );
+
+    // Bounded audit log keyed by entry id, oldest first
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    // Every version of every product keyed by (product id, version)
+    static PRODUCT_HISTORY: RefCell<StableBTreeMap<(u64, u64), ProductRevision, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    // Index of PRODUCT_HISTORY keyed by (product id, recorded_at), pointing at
+    // the latest version written at that time
+    static PRODUCT_HISTORY_TIMES: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    static HISTORY_SETTINGS: RefCell<Cell<HistorySettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))),
+            HistorySettings::default(),
+        )
+        .expect("Cannot create the history settings cell")
+    );
+
+    // Encoded snapshot prepared by create_backup, kept on the heap while it is
+    // downloaded. It does not survive an upgrade.
+    static BACKUP: RefCell<Option<(BackupManifest, Vec<u8>)>> = const { RefCell::new(None) };
+
+    // Snapshot being uploaded for a restore, kept on the heap until finish_restore.
+    // It does not survive an upgrade, which means starting the restore again.
+    static RESTORE: RefCell<Option<(BackupManifest, Vec<u8>)>> = const { RefCell::new(None) };
+}
+
+// Product payload struct used to create or update a product
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ProductPayload {
+    name: String,
+    // Initial stock, given in `unit`
+    quantity: u32,
+    unit: Unit,
+    category_id: u64,
+    // Sale price and unit cost of one `unit`, stored per base unit
+    price: u64,
+    unit_cost: u64,
+    currency: String,
+}
+
+// Category payload struct used to create or update a category
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct CategoryPayload {
+    name: String,
+    parent_id: Option<u64>,
+    display_order: u32,
+}
+
+// Payload for changing the price of a product, per base unit of the product
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct PricePayload {
+    price: u64,
+    unit_cost: u64,
+    currency: String,
+}
+
+// Payload for adding or removing stock
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct StockPayload {
+    amount: u32,
+    // Unit of `amount`, defaults to the product's unit
+    unit: Option<Unit>,
+    // Batch details, only used when adding stock
+    lot_code: Option<String>,
+    baked_at: Option<u64>,
+    best_before: Option<u64>,
+}
+
+// A single requested line item of an order
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct OrderLinePayload {
+    product_id: u64,
+    quantity: u32,
+}
+
+// Payload for placing an order. Stock held by the listed reservations, which
+// must belong to the caller, is used for the order. Each reservation is reduced
+// by the amount the order takes from it and removed once used up.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct OrderPayload {
+    lines: Vec<OrderLinePayload>,
+    reservation_ids: Vec<u64>,
+}
+
+// Payload for reserving stock of a product
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ReservationPayload {
+    product_id: u64,
+    quantity: u32,
+    ttl_seconds: u64,
+}
+
+// Stock of a product split into what is on hand, held by reservations and free to sell
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct StockLevel {
+    on_hand: u32,
+    reserved: u32,
+    available: u32,
+    unit: Unit,
+}
+
+// Payload used to create or update an ingredient
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct IngredientPayload {
+    name: String,
+    // Stock on hand, given in `unit`
+    quantity: u64,
+    unit: Unit,
+}
+
+// Payload for adding stock to an ingredient
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct IngredientStockPayload {
+    amount: u64,
+    // Unit of `amount`, defaults to the ingredient's unit
+    unit: Option<Unit>,
+}
+
+// Payload for setting the recipe of a product
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct RecipePayload {
+    lines: Vec<RecipeLinePayload>,
+}
+
+// A single ingredient line of a recipe payload
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct RecipeLinePayload {
+    ingredient_id: u64,
+    quantity: u64,
+}
+
+// Payload for producing finished goods from their recipe
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ProducePayload {
+    quantity: u32,
+    lot_code: Option<String>,
+    baked_at: Option<u64>,
+    best_before: Option<u64>,
+}
+
+// Payload for setting when and how much of a product to reorder.
+// Quantities are in the product's unit.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ReorderPayload {
+    // None stops tracking the product for reordering
+    reorder_point: Option<u32>,
+    reorder_quantity: u32,
+}
+
+// Supplier payload struct used to create or update a supplier
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct SupplierPayload {
+    name: String,
+    contact: String,
+    lead_time_days: u32,
+    items: Vec<SuppliedItem>,
+}
+
+// Purchase order payload struct used to draft a purchase order
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct PurchaseOrderPayload {
+    supplier_id: u64,
+    lines: Vec<PurchaseOrderLinePayload>,
+}
+
+// A single line of a purchase order, with the quantity in the item's base unit
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct PurchaseOrderLinePayload {
+    item: SuppliedItem,
+    quantity: u64,
+    unit_cost: u64,
+}
+
+// Payload for receiving some or all of the outstanding lines of a purchase order
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ReceivePayload {
+    lines: Vec<ReceivedLinePayload>,
+}
+
+// Quantity delivered for one purchase order line, identified by its position.
+// Batch details only apply to product lines.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ReceivedLinePayload {
+    line: u32,
+    quantity: u64,
+    lot_code: Option<String>,
+    baked_at: Option<u64>,
+    best_before: Option<u64>,
+}
+
+// A principal together with the role it was granted
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct RoleAssignment {
+    principal: Principal,
+    role: Role,
+}
+
+// Payload for listing products page by page, with optional filters.
+// All range bounds are inclusive.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ListProductsPayload {
+    cursor: Option<u64>,
+    limit: u32,
+    category_id: Option<u64>,
+    min_quantity: Option<u32>,
+    max_quantity: Option<u32>,
+    min_created_at: Option<u64>,
+    max_created_at: Option<u64>,
+    min_updated_at: Option<u64>,
+    max_updated_at: Option<u64>,
+    // Archived products are left out unless this is set
+    include_archived: bool,
+}
+
+// A single page of products along with the cursor to fetch the next one
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ProductPage {
+    products: Vec<Product>,
+    next_cursor: Option<u64>,
+}
+
+// A single page of price changes along with the cursor (change time) to fetch the next one
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct PriceHistoryPage {
+    changes: Vec<PriceChange>,
+    next_cursor: Option<u64>,
+}
+
+// A single page of orders along with the cursor to fetch the next one
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct OrderPage {
+    orders: Vec<Order>,
+    next_cursor: Option<u64>,
+}
+
+// A single page of ingredients along with the cursor to fetch the next one
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct IngredientPage {
+    ingredients: Vec<Ingredient>,
+    next_cursor: Option<u64>,
+}
+
+// A single page of waste records along with the cursor to fetch the next one
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct WastePage {
+    records: Vec<WasteRecord>,
+    next_cursor: Option<u64>,
+}
+
+// A product matching a search along with its relevance, higher is better
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SearchResult {
+    product: Product,
+    score: u32,
+}
+
+// A single page of search results, best match first, along with the cursor
+// (offset into the results) to fetch the next one
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct SearchPage {
+    results: Vec<SearchResult>,
+    next_cursor: Option<u64>,
+}
+
+// A single page of suppliers along with the cursor to fetch the next one
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct SupplierPage {
+    suppliers: Vec<Supplier>,
+    next_cursor: Option<u64>,
+}
+
+// A single page of purchase orders along with the cursor to fetch the next one
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct PurchaseOrderPage {
+    purchase_orders: Vec<PurchaseOrder>,
+    next_cursor: Option<u64>,
+}
+
+// Filters for listing audit entries. Time bounds are inclusive.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct AuditFilter {
+    caller: Option<Principal>,
+    method: Option<String>,
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+// A single page of audit entries along with the cursor to fetch the next one
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct AuditPage {
+    entries: Vec<AuditEntry>,
+    next_cursor: Option<u64>,
+}
+
+// A single page of product versions along with the cursor (version) to fetch the next one
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ProductHistoryPage {
+    revisions: Vec<ProductRevision>,
+    next_cursor: Option<u64>,
+}
+
+// A single page of stock movements along with the cursor to fetch the next one
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct MovementPage {
+    movements: Vec<StockMovement>,
+    next_cursor: Option<u64>,
+}
+
+// Function to validate ProductPayload inputs for a new product, which must
+// start with some stock
+fn validate_new_product_payload(payload: &ProductPayload) -> Result<(), Error> {
+    if payload.quantity == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "Product quantity must be greater than zero.".to_string(),
+        });
+    }
+    validate_product_payload(payload)
+}
+
+// Function to validate ProductPayload inputs. An update may set the quantity
+// to zero, as a sold out product has no stock left.
+fn validate_product_payload(payload: &ProductPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_PRODUCT_NAME_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Product name must be between 1 and {} characters.",
+                MAX_PRODUCT_NAME_LENGTH
+            ),
+        });
+    }
+    if _get_category(&payload.category_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("A category with id={} was not found", payload.category_id),
+        });
+    }
+    validate_currency(&payload.currency)
+}
+
+// Function to validate CategoryPayload inputs for the category with the given
+// id, or for a new category when there is none
+fn validate_category_payload(payload: &CategoryPayload, id: Option<u64>) -> Result<(), Error> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.len() > MAX_CATEGORY_NAME_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Category name must be between 1 and {} characters.",
+                MAX_CATEGORY_NAME_LENGTH
+            ),
+        });
+    }
+
+    // Walk up from the parent to make sure it exists and the hierarchy stays a tree
+    let mut ancestor = payload.parent_id;
+    while let Some(ancestor_id) = ancestor {
+        if Some(ancestor_id) == id {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Category with id={} cannot be placed under its own subcategory",
+                    ancestor_id
+                ),
+            });
+        }
+        let category = _get_category(&ancestor_id).ok_or(Error::NotFound {
+            msg: format!("A parent category with id={} was not found", ancestor_id),
+        })?;
+        ancestor = category.parent_id;
+    }
+
+    let duplicate = CATEGORIES.with(|categories| {
+        categories.borrow().iter().any(|(other_id, other)| {
+            Some(other_id) != id
+                && other.parent_id == payload.parent_id
+                && other.name.eq_ignore_ascii_case(name)
+        })
+    });
+    if duplicate {
+        return Err(Error::InvalidOperation {
+            msg: format!("A category named '{}' already exists at this level", name),
+        });
+    }
+    Ok(())
+}
+
+// Function to validate PricePayload inputs
+fn validate_price_payload(payload: &PricePayload) -> Result<(), Error> {
+    validate_currency(&payload.currency)
+}
+
+// Function to validate a currency code, which must be three uppercase letters (ISO 4217)
+fn validate_currency(currency: &str) -> Result<(), Error> {
+    if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_uppercase()) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Invalid currency code '{}'. Expected three uppercase letters, e.g. USD.",
+                currency
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to validate StockPayload inputs
+fn validate_stock_payload(payload: &StockPayload) -> Result<(), Error> {
+    if payload.amount == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "Stock amount must be greater than zero.".to_string(),
+        });
+    }
+    if let Some(lot_code) = &payload.lot_code {
+        if lot_code.trim().is_empty() || lot_code.len() > MAX_LOT_CODE_LENGTH {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Lot code must be between 1 and {} characters.",
+                    MAX_LOT_CODE_LENGTH
+                ),
+            });
+        }
+    }
+    if let (Some(baked_at), Some(best_before)) = (payload.baked_at, payload.best_before) {
+        if best_before <= baked_at {
+            return Err(Error::InvalidOperation {
+                msg: "Best before date must be after the baked at date.".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Function to validate OrderPayload inputs
+fn validate_order_payload(payload: &OrderPayload) -> Result<(), Error> {
+    if payload.lines.is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: "An order must contain at least one line.".to_string(),
+        });
+    }
+    if payload.lines.len() > MAX_ORDER_LINES {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "An order cannot contain more than {} lines.",
+                MAX_ORDER_LINES
+            ),
+        });
+    }
+    if let Some(line) = payload.lines.iter().find(|line| line.quantity == 0) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Order quantity for product id={} must be greater than zero.",
+                line.product_id
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to validate ReservationPayload inputs
+fn validate_reservation_payload(payload: &ReservationPayload) -> Result<(), Error> {
+    if payload.quantity == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "Reserved quantity must be greater than zero.".to_string(),
+        });
+    }
+    if payload.ttl_seconds == 0 || payload.ttl_seconds > MAX_RESERVATION_TTL_SECONDS {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Reservation ttl must be between 1 and {} seconds.",
+                MAX_RESERVATION_TTL_SECONDS
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to validate IngredientPayload inputs
+fn validate_ingredient_payload(payload: &IngredientPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_INGREDIENT_NAME_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Ingredient name must be between 1 and {} characters.",
+                MAX_INGREDIENT_NAME_LENGTH
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to validate RecipePayload inputs
+fn validate_recipe_payload(payload: &RecipePayload) -> Result<(), Error> {
+    if payload.lines.is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: "A recipe must contain at least one ingredient.".to_string(),
+        });
+    }
+    if payload.lines.len() > MAX_RECIPE_LINES {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A recipe cannot contain more than {} ingredients.",
+                MAX_RECIPE_LINES
+            ),
+        });
+    }
+    for (index, line) in payload.lines.iter().enumerate() {
+        if line.quantity == 0 {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Recipe quantity for ingredient id={} must be greater than zero.",
+                    line.ingredient_id
+                ),
+            });
+        }
+        if payload.lines[..index]
+            .iter()
+            .any(|other| other.ingredient_id == line.ingredient_id)
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Ingredient id={} is listed more than once in the recipe.",
+                    line.ingredient_id
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Function to validate ProducePayload inputs, reusing the batch checks of StockPayload
+fn validate_produce_payload(payload: &ProducePayload) -> Result<(), Error> {
+    validate_stock_payload(&StockPayload {
+        amount: payload.quantity,
+        unit: None,
+        lot_code: payload.lot_code.clone(),
+        baked_at: payload.baked_at,
+        best_before: payload.best_before,
+    })
+}
+
+// Function to validate ReorderPayload inputs
+fn validate_reorder_payload(payload: &ReorderPayload) -> Result<(), Error> {
+    if payload.reorder_point.is_some() && payload.reorder_quantity == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "Reorder quantity must be greater than zero.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Function to validate SupplierPayload inputs
+fn validate_supplier_payload(payload: &SupplierPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_SUPPLIER_NAME_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Supplier name must be between 1 and {} characters.",
+                MAX_SUPPLIER_NAME_LENGTH
+            ),
+        });
+    }
+    if payload.contact.len() > MAX_CONTACT_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Supplier contact cannot be longer than {} characters.",
+                MAX_CONTACT_LENGTH
+            ),
+        });
+    }
+    if payload.lead_time_days > MAX_LEAD_TIME_DAYS {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Supplier lead time cannot be longer than {} days.",
+                MAX_LEAD_TIME_DAYS
+            ),
+        });
+    }
+    if payload.items.len() > MAX_SUPPLIED_ITEMS {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A supplier cannot supply more than {} items.",
+                MAX_SUPPLIED_ITEMS
+            ),
+        });
+    }
+    for (index, item) in payload.items.iter().enumerate() {
+        if payload.items[..index].contains(item) {
+            return Err(Error::InvalidOperation {
+                msg: format!("{:?} is listed more than once", item),
+            });
+        }
+        ensure_item_exists(item)?;
+    }
+    Ok(())
+}
+
+// Function to validate PurchaseOrderPayload inputs
+fn validate_purchase_order_payload(payload: &PurchaseOrderPayload) -> Result<(), Error> {
+    if payload.lines.is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: "A purchase order must contain at least one line.".to_string(),
+        });
+    }
+    if payload.lines.len() > MAX_PURCHASE_ORDER_LINES {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A purchase order cannot contain more than {} lines.",
+                MAX_PURCHASE_ORDER_LINES
+            ),
+        });
+    }
+    if let Some(line) = payload.lines.iter().find(|line| line.quantity == 0) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Ordered quantity of {:?} must be greater than zero.",
+                line.item
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to validate ListProductsPayload inputs
+fn validate_list_products_payload(payload: &ListProductsPayload) -> Result<(), Error> {
+    let ranges = [
+        (
+            "quantity",
+            payload.min_quantity.map(u64::from),
+            payload.max_quantity.map(u64::from),
+        ),
+        ("created_at", payload.min_created_at, payload.max_created_at),
+        ("updated_at", payload.min_updated_at, payload.max_updated_at),
+    ];
+    for (field, min, max) in ranges {
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                return Err(Error::InvalidOperation {
+                    msg: format!(
+                        "Invalid {} range: minimum {} is greater than maximum {}",
+                        field, min, max
+                    ),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// Helper function to check whether a product matches the listing filters
+fn matches_filters(product: &Product, payload: &ListProductsPayload) -> bool {
+    let in_range = |value: u64, min: Option<u64>, max: Option<u64>| {
+        min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+    };
+
+    if product.archived_at.is_some() && !payload.include_archived {
+        return false;
+    }
+    if let Some(category_id) = payload.category_id {
+        if product.category_id != category_id {
+            return false;
+        }
+    }
+    if !in_range(
+        product.quantity as u64,
+        payload.min_quantity.map(u64::from),
+        payload.max_quantity.map(u64::from),
+    ) {
+        return false;
+    }
+    if !in_range(
+        product.created_at,
+        payload.min_created_at,
+        payload.max_created_at,
+    ) {
+        return false;
+    }
+    if payload.min_updated_at.is_some() || payload.max_updated_at.is_some() {
+        match product.updated_at {
+            Some(updated_at) => {
+                in_range(updated_at, payload.min_updated_at, payload.max_updated_at)
+            }
+            None => false,
+        }
+    } else {
+        true
+    }
+}
+
+// Helper function to look up the role of a principal
+fn _get_role(principal: &Principal) -> Option<Role> {
+    ROLES.with(|roles| roles.borrow().get(&StorablePrincipal(*principal)))
+}
+
+// Function to check that the caller holds at least the required role
+fn ensure_role(required: Role) -> Result<Principal, Error> {
+    let principal = caller();
+    match _get_role(&principal) {
+        Some(role) if role >= required => Ok(principal),
+        _ => Err(Error::Unauthorized {
+            msg: format!("Caller {} does not have the required role", principal),
+        }),
+    }
+}
+
+// Helper function to count the principals holding the owner role
+fn owner_count() -> usize {
+    ROLES.with(|roles| {
+        roles
+            .borrow()
+            .iter()
+            .filter(|(_, role)| *role == Role::Owner)
+            .count()
+    })
+}
+
+// Helper function to retrieve a product by its ID
+fn _get_product(id: &u64) -> Option<Product> {
+    STORAGE.with(|service| service.borrow().get(id))
+}
+
+// Query function to retrieve a product by ID
+#[ic_cdk::query]
+fn get_product(id: u64) -> Result<Product, Error> {
+    match _get_product(&id) {
+        Some(product) => Ok(product),
+        None => Err(Error::NotFound {
+            msg: format!("A product with id={} was not found", id),
+        }),
+    }
+}
+
+// Query function to page through the recorded versions of a product, oldest
+// first. Every version is kept unless the Owner limits the history with
+// set_product_history_retention.
+#[ic_cdk::query]
+fn get_product_history(id: u64, cursor: Option<u64>, limit: u32) -> ProductHistoryPage {
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded((id, cursor)),
+        None => Bound::Included((id, 0)),
+    };
+    let limit = page_limit(limit);
+    let mut revisions: Vec<ProductRevision> = PRODUCT_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range((start, Bound::Included((id, u64::MAX))))
+            .map(|(_, revision)| revision)
+            .take(limit + 1)
+            .collect()
+    });
+    let next_cursor = if revisions.len() > limit {
+        revisions.truncate(limit);
+        revisions.last().map(|revision| revision.product.version)
+    } else {
+        None
+    };
+    ProductHistoryPage {
+        revisions,
+        next_cursor,
+    }
+}
+
+// Query function to get a product as it was at the given timestamp, that is
+// its latest version written at or before that time. Times before the oldest
+// kept version are not found.
+#[ic_cdk::query]
+fn get_product_at(id: u64, timestamp: u64) -> Result<Product, Error> {
+    let version = PRODUCT_HISTORY_TIMES.with(|times| {
+        times
+            .borrow()
+            .iter_upper_bound(&(id, timestamp.saturating_add(1)))
+            .next()
+            .filter(|((product_id, _), _)| *product_id == id)
+            .map(|(_, version)| version)
+    });
+    version
+        .and_then(|version| PRODUCT_HISTORY.with(|history| history.borrow().get(&(id, version))))
+        .map(|revision| revision.product)
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Product with id={} has no recorded version at {}",
+                id, timestamp
+            ),
+        })
+}
+
+// Query function to get the current stock of a product by ID
+#[ic_cdk::query]
+fn get_stock(id: u64) -> Result<StockLevel, Error> {
+    match _get_product(&id) {
+        Some(product) => Ok(stock_level(&product)),
+        None => Err(Error::NotFound {
+            msg: format!("A product with id={} was not found", id),
+        }),
+    }
+}
+
+// Query function to list products in id order, one page at a time
+#[ic_cdk::query]
+fn list_products(payload: ListProductsPayload) -> Result<ProductPage, Error> {
+    validate_list_products_payload(&payload)?;
+
+    let limit = page_limit(payload.limit);
+
+    // Fetch one extra product to know whether another page exists.
+    // When filtering by category, only walk the category index.
+    let mut products: Vec<Product> = match payload.category_id {
+        Some(category_id) => {
+            let start = match payload.cursor {
+                Some(cursor) => Bound::Excluded((category_id, cursor)),
+                None => Bound::Included((category_id, 0)),
+            };
+            CATEGORY_INDEX.with(|index| {
+                index
+                    .borrow()
+                    .range((start, Bound::Included((category_id, u64::MAX))))
+                    .filter_map(|((_, id), _)| _get_product(&id))
+                    .filter(|product| matches_filters(product, &payload))
+                    .take(limit + 1)
+                    .collect()
+            })
+        }
+        None => {
+            let start = match payload.cursor {
+                Some(cursor) => Bound::Excluded(cursor),
+                None => Bound::Unbounded,
+            };
+            STORAGE.with(|service| {
+                service
+                    .borrow()
+                    .range((start, Bound::Unbounded))
+                    .map(|(_, product)| product)
+                    .filter(|product| matches_filters(product, &payload))
+                    .take(limit + 1)
+                    .collect()
+            })
+        }
+    };
+
+    let next_cursor = if products.len() > limit {
+        products.truncate(limit);
+        products.last().map(|product| product.id)
+    } else {
+        None
+    };
+
+    Ok(ProductPage {
+        products,
+        next_cursor,
+    })
+}
+
+// Helper function to clamp a requested page size to MAX_PAGE_SIZE
+fn page_limit(limit: u32) -> usize {
+    let limit = match limit {
+        0 => MAX_PAGE_SIZE,
+        limit => limit.min(MAX_PAGE_SIZE),
+    };
+    limit as usize
+}
+
+// Helper function to retrieve a category by its ID
+fn _get_category(id: &u64) -> Option<Category> {
+    CATEGORIES.with(|categories| categories.borrow().get(id))
+}
+
+// Function to store the categories that replace the legacy categories, under
+// the ids products of those categories are migrated to
+fn seed_categories() {
+    let now = time();
+    for (id, name) in SEEDED_CATEGORIES.iter().enumerate() {
+        let id = id as u64;
+        if _get_category(&id).is_some() {
+            continue;
+        }
+        let category = Category {
+            id,
+            name: name.to_string(),
+            parent_id: None,
+            display_order: id as u32,
+            created_at: now,
+            updated_at: None,
+        };
+        CATEGORIES.with(|categories| categories.borrow_mut().insert(id, category));
+    }
+    CATEGORY_ID_COUNTER.with(|counter| {
+        let current_value = *counter.borrow().get();
+        let seeded = SEEDED_CATEGORIES.len() as u64;
+        if current_value < seeded {
+            counter
+                .borrow_mut()
+                .set(seeded)
+                .expect("Cannot increment category id counter");
+        }
+    });
+}
+
+// Function to add a new category
+fn _add_category(payload: CategoryPayload) -> Result<Category, Error> {
+    ensure_role(Role::Manager)?;
+
+    // Validate payload before processing
+    validate_category_payload(&payload, None)?;
+
+    let id = CATEGORY_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment category id counter");
+
+    let category = Category {
+        id,
+        name: payload.name.trim().to_string(),
+        parent_id: payload.parent_id,
+        display_order: payload.display_order,
+        created_at: time(),
+        updated_at: None,
+    };
+    CATEGORIES.with(|categories| categories.borrow_mut().insert(id, category.clone()));
+    Ok(category)
+}
+
+// Update endpoint of _add_category, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn add_category(payload: CategoryPayload) -> Result<Category, Error> {
+    audited(
+        "add_category",
+        None,
+        |category: &Category| Some(AuditTarget::Id(category.id)),
+        || _add_category(payload),
+    )
+}
+
+// Function to rename, move or reorder a category
+fn _update_category(id: u64, payload: CategoryPayload) -> Result<Category, Error> {
+    ensure_role(Role::Manager)?;
+
+    match _get_category(&id) {
+        Some(mut category) => {
+            validate_category_payload(&payload, Some(id))?;
+            category.name = payload.name.trim().to_string();
+            category.parent_id = payload.parent_id;
+            category.display_order = payload.display_order;
+            category.updated_at = Some(time());
+            CATEGORIES.with(|categories| categories.borrow_mut().insert(id, category.clone()));
+            Ok(category)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't update a category with id={}. Category not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _update_category, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn update_category(id: u64, payload: CategoryPayload) -> Result<Category, Error> {
+    audited(
+        "update_category",
+        _get_category(&id).as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _update_category(id, payload),
+    )
+}
+
+// Function to remove a category that has no products and no subcategories
+fn _remove_category(id: u64) -> Result<Category, Error> {
+    ensure_role(Role::Manager)?;
+
+    let has_products = CATEGORY_INDEX.with(|index| {
+        index
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .next()
+            .is_some()
+    });
+    if has_products {
+        return Err(Error::InvalidOperation {
+            msg: format!("Category with id={} still has products", id),
+        });
+    }
+    let has_children = CATEGORIES.with(|categories| {
+        categories
+            .borrow()
+            .iter()
+            .any(|(_, category)| category.parent_id == Some(id))
+    });
+    if has_children {
+        return Err(Error::InvalidOperation {
+            msg: format!("Category with id={} still has subcategories", id),
+        });
+    }
+
+    match CATEGORIES.with(|categories| categories.borrow_mut().remove(&id)) {
+        Some(category) => Ok(category),
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't delete a category with id={}. Category not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _remove_category, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn remove_category(id: u64) -> Result<Category, Error> {
+    audited(
+        "remove_category",
+        _get_category(&id).as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _remove_category(id),
+    )
+}
+
+// Query function to retrieve a category by ID
+#[ic_cdk::query]
+fn get_category(id: u64) -> Result<Category, Error> {
+    match _get_category(&id) {
+        Some(category) => Ok(category),
+        None => Err(Error::NotFound {
+            msg: format!("A category with id={} was not found", id),
+        }),
+    }
+}
+
+// Query function to list the subcategories of a category, or the top level
+// categories when no parent is given, in display order
+#[ic_cdk::query]
+fn list_categories(parent_id: Option<u64>) -> Vec<Category> {
+    let mut categories: Vec<Category> = CATEGORIES.with(|categories| {
+        categories
+            .borrow()
+            .iter()
+            .map(|(_, category)| category)
+            .filter(|category| category.parent_id == parent_id)
+            .collect()
+    });
+    categories.sort_by_key(|category| (category.display_order, category.id));
+    categories
+}
+
+// Query function to list the products of a category using the category index
+#[ic_cdk::query]
+fn get_products_by_category(
+    category_id: u64,
+    cursor: Option<u64>,
+    limit: u32,
+) -> Result<ProductPage, Error> {
+    list_products(ListProductsPayload {
+        cursor,
+        limit,
+        category_id: Some(category_id),
+        ..Default::default()
+    })
+}
+
+// Function to insert a product into the stable storage, keeping the category
+// and search indexes in sync. The product's version is bumped as it is stored.
+fn do_insert(product: &mut Product) {
+    product.version += 1;
+    let previous = STORAGE.with(|service| service.borrow_mut().insert(product.id, product.clone()));
+    record_revision(product);
+    CATEGORY_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = &previous {
+            index.remove(&(previous.category_id, previous.id));
+        }
+        index.insert((product.category_id, product.id), ());
+    });
+    match previous {
+        Some(previous) if previous.name == product.name => {}
+        Some(previous) => {
+            unindex_name(&previous);
+            index_name(product);
+        }
+        None => index_name(product),
+    }
+}
+
+// Function to keep a copy of a product version in the product history and
+// drop the versions that fall outside the configured retention
+fn record_revision(product: &Product) {
+    insert_revision(ProductRevision {
+        recorded_at: time(),
+        product: product.clone(),
+    });
+
+    let settings = HISTORY_SETTINGS.with(|cell| cell.borrow().get().clone());
+    let Some(max_revisions) = settings.max_revisions else {
+        return;
+    };
+    // Versions are consecutive, so the expired ones are the oldest of the product.
+    // Usually that is a single version, more right after the retention was lowered.
+    let Some(expired) = product.version.checked_sub(max_revisions) else {
+        return;
+    };
+    let removed: Vec<ProductRevision> = PRODUCT_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let keys: Vec<(u64, u64)> = history
+            .range((product.id, 0)..=(product.id, expired))
+            .map(|(key, _)| key)
+            .collect();
+        keys.iter().filter_map(|key| history.remove(key)).collect()
+    });
+    PRODUCT_HISTORY_TIMES.with(|times| {
+        let mut times = times.borrow_mut();
+        for revision in removed {
+            let key = (product.id, revision.recorded_at);
+            // A later version written at the same time keeps the index entry
+            if times.get(&key) == Some(revision.product.version) {
+                times.remove(&key);
+            }
+        }
+    });
+}
+
+// Function to store a product revision along with its time index entry
+fn insert_revision(revision: ProductRevision) {
+    let (id, version) = (revision.product.id, revision.product.version);
+    PRODUCT_HISTORY_TIMES.with(|times| {
+        times
+            .borrow_mut()
+            .insert((id, revision.recorded_at), version)
+    });
+    PRODUCT_HISTORY.with(|history| history.borrow_mut().insert((id, version), revision));
+}
+
+// Function to drop the whole history of a product
+fn remove_product_history(id: u64) {
+    PRODUCT_HISTORY_TIMES.with(|times| {
+        let mut times = times.borrow_mut();
+        let keys: Vec<(u64, u64)> = times
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            times.remove(&key);
+        }
+    });
+    PRODUCT_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let keys: Vec<(u64, u64)> = history
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            history.remove(&key);
+        }
+    });
+}
+
+// Function to record the current version of every product that has no history yet
+fn seed_product_history() {
+    let products: Vec<Product> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .collect()
+    });
+    for product in products {
+        let recorded = PRODUCT_HISTORY.with(|history| {
+            history
+                .borrow()
+                .contains_key(&(product.id, product.version))
+        });
+        if !recorded {
+            insert_revision(ProductRevision {
+                recorded_at: product.updated_at.unwrap_or(product.created_at),
+                product,
+            });
+        }
+    }
+}
+
+// Function to remove a product from the stable storage along with its index entry
+fn do_remove(id: &u64) -> Option<Product> {
+    let removed = STORAGE.with(|service| service.borrow_mut().remove(id));
+    if let Some(product) = &removed {
+        CATEGORY_INDEX.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(product.category_id, product.id))
+        });
+        unindex_name(product);
+    }
+    removed
+}
+
+// Function to rebuild the category index from the products in storage
+fn rebuild_category_index() {
+    CATEGORY_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let stale: Vec<(u64, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in stale {
+            index.remove(&key);
+        }
+        STORAGE.with(|service| {
+            for (id, product) in service.borrow().iter() {
+                index.insert((product.category_id, id), ());
+            }
+        });
+    });
+}
+
+// Function to split text into lowercase search tokens. Words are runs of
+// letters and digits, cut to MAX_TOKEN_LENGTH bytes.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    for word in text.split(|c: char| !c.is_alphanumeric()) {
+        let mut token = String::new();
+        for c in word.chars().flat_map(char::to_lowercase) {
+            if token.len() + c.len_utf8() > MAX_TOKEN_LENGTH {
+                break;
+            }
+            token.push(c);
+        }
+        if !token.is_empty() && !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+// Function to add the name of a product to the search index
+fn index_name(product: &Product) {
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for token in tokenize(&product.name) {
+            index.insert((SearchToken(token), product.id), ());
+        }
+    });
+}
+
+// Function to remove the name of a product from the search index
+fn unindex_name(product: &Product) {
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for token in tokenize(&product.name) {
+            index.remove(&(SearchToken(token), product.id));
+        }
+    });
+}
+
+// Function to rebuild the search index from the products in storage
+fn rebuild_search_index() {
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let stale: Vec<(SearchToken, u64)> = index.iter().map(|(key, _)| key).collect();
+        for key in stale {
+            index.remove(&key);
+        }
+    });
+    STORAGE.with(|service| {
+        for (_, product) in service.borrow().iter() {
+            index_name(&product);
+        }
+    });
+}
+
+// Helper function to score the products with a token starting with `prefix`.
+// Whole word matches score 2, prefix matches 1.
+fn match_prefix(prefix: &str) -> BTreeMap<u64, u32> {
+    let mut scores: BTreeMap<u64, u32> = BTreeMap::new();
+    SEARCH_INDEX.with(|index| {
+        let index = index.borrow();
+        let start = (SearchToken(prefix.to_string()), 0);
+        for ((token, id), _) in index
+            .range(start..)
+            .take_while(|((token, _), _)| token.0.starts_with(prefix))
+        {
+            let score = if token.0 == prefix { 2 } else { 1 };
+            let best = scores.entry(id).or_insert(0);
+            *best = (*best).max(score);
+        }
+    });
+    scores
+}
+
+// Query function to search active products by name. Every word of the query
+// must match the start of a word in the product name, ignoring case. Results
+// are ranked by how many words match whole, then by id.
+#[ic_cdk::query]
+fn search_products(query: String, cursor: Option<u64>, limit: u32) -> Result<SearchPage, Error> {
+    let tokens = tokenize(&query);
+    if tokens.is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: "Search query must contain at least one letter or digit.".to_string(),
+        });
+    }
+
+    // Intersect the matches of every query token, adding up their scores
+    let mut scores = match_prefix(&tokens[0]);
+    for token in &tokens[1..] {
+        let matches = match_prefix(token);
+        scores.retain(|id, score| match matches.get(id) {
+            Some(extra) => {
+                *score += extra;
+                true
+            }
+            None => false,
+        });
+    }
+
+    let mut ranked: Vec<SearchResult> = scores
+        .into_iter()
+        .filter_map(|(id, score)| _get_product(&id).map(|product| SearchResult { product, score }))
+        .filter(|result| result.product.archived_at.is_none())
+        .collect();
+    ranked.sort_by(|a, b| b.score.cmp(&a.score).then(a.product.id.cmp(&b.product.id)));
+
+    // An offset past what usize can hold is past every result
+    let offset = usize::try_from(cursor.unwrap_or(0)).unwrap_or(usize::MAX);
+    let limit = page_limit(limit);
+    let total = ranked.len();
+    let results: Vec<SearchResult> = ranked.into_iter().skip(offset).take(limit).collect();
+    let end = offset.saturating_add(limit);
+    let next_cursor = if total > end { Some(end as u64) } else { None };
+    Ok(SearchPage {
+        results,
+        next_cursor,
+    })
+}
+
+// Function to append a stock movement for a product to the ledger
+fn record_movement(product: &Product, delta: i64, reason: MovementReason) {
+    record_supplied_movement(product, delta, reason, None);
+}
+
+// Function to append a stock movement for a product to the ledger, noting the
+// supplier that delivered the stock
+fn record_supplied_movement(
+    product: &Product,
+    delta: i64,
+    reason: MovementReason,
+    supplier_id: Option<u64>,
+) {
+    MOVEMENTS.with(|log| {
+        let log = log.borrow();
+        let movement = StockMovement {
+            id: log.len(),
+            product_id: product.id,
+            delta,
+            reason,
+            caller: caller(),
+            timestamp: time(),
+            resulting_quantity: product.quantity,
+            supplier_id,
+        };
+        let id = log
+            .append(&movement)
+            .expect("Cannot append to the movement log");
+        PRODUCT_MOVEMENTS.with(|index| index.borrow_mut().insert((product.id, id), ()));
+    });
+}
+
+// Helper function to build a page of movements from an iterator of movement ids
+fn movement_page(ids: impl Iterator<Item = u64>, limit: usize) -> MovementPage {
+    let mut movements: Vec<StockMovement> = MOVEMENTS.with(|log| {
+        let log = log.borrow();
+        ids.filter_map(|id| log.get(id)).take(limit + 1).collect()
+    });
+    let next_cursor = if movements.len() > limit {
+        movements.truncate(limit);
+        movements.last().map(|movement| movement.id)
+    } else {
+        None
+    };
+    MovementPage {
+        movements,
+        next_cursor,
+    }
+}
+
+// Query function to page through every stock movement in the order they happened
+#[ic_cdk::query]
+fn list_stock_movements(cursor: Option<u64>, limit: u32) -> MovementPage {
+    // Nothing can follow the largest possible cursor
+    let Some(start) = cursor.map_or(Some(0), |cursor| cursor.checked_add(1)) else {
+        return MovementPage::default();
+    };
+    let end = MOVEMENTS.with(|log| log.borrow().len());
+    movement_page(start..end, page_limit(limit))
+}
+
+// Query function to page through the stock movements of a single product
+#[ic_cdk::query]
+fn get_product_movements(product_id: u64, cursor: Option<u64>, limit: u32) -> MovementPage {
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded((product_id, cursor)),
+        None => Bound::Included((product_id, 0)),
+    };
+    let limit = page_limit(limit);
+    let ids: Vec<u64> = PRODUCT_MOVEMENTS.with(|index| {
+        index
+            .borrow()
+            .range((start, Bound::Included((product_id, u64::MAX))))
+            .map(|((_, id), _)| id)
+            .take(limit + 1)
+            .collect()
+    });
+    movement_page(ids.into_iter(), limit)
+}
+
+// Function to record the current price of a product in its price history
+fn record_price_change(product: &Product) {
+    let change = PriceChange {
+        product_id: product.id,
+        price: product.price,
+        unit_cost: product.unit_cost,
+        currency: product.currency.clone(),
+        changed_by: caller(),
+        changed_at: time(),
+    };
+    PRICE_HISTORY.with(|history| {
+        history
+            .borrow_mut()
+            .insert((change.product_id, change.changed_at), change)
+    });
+}
+
+// Helper function to check whether the price fields of a product differ from a payload
+fn price_differs(product: &Product, price: u64, unit_cost: u64, currency: &str) -> bool {
+    product.price != price || product.unit_cost != unit_cost || product.currency != currency
+}
+
+// Query function to get the price of a product that was in effect at the given timestamp
+#[ic_cdk::query]
+fn get_price_at(id: u64, timestamp: u64) -> Result<PriceChange, Error> {
+    let change = PRICE_HISTORY.with(|history| {
+        history
+            .borrow()
+            .iter_upper_bound(&(id, timestamp.saturating_add(1)))
+            .next()
+            .filter(|((product_id, _), _)| *product_id == id)
+            .map(|(_, change)| change)
+    });
+    change.ok_or(Error::NotFound {
+        msg: format!(
+            "No price was in effect for product id={} at {}",
+            id, timestamp
+        ),
+    })
+}
+
+// Query function to page through the price history of a product, oldest first
+#[ic_cdk::query]
+fn get_price_history(id: u64, cursor: Option<u64>, limit: u32) -> PriceHistoryPage {
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded((id, cursor)),
+        None => Bound::Included((id, 0)),
+    };
+    let limit = page_limit(limit);
+    let mut changes: Vec<PriceChange> = PRICE_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range((start, Bound::Included((id, u64::MAX))))
+            .map(|(_, change)| change)
+            .take(limit + 1)
+            .collect()
+    });
+    let next_cursor = if changes.len() > limit {
+        changes.truncate(limit);
+        changes.last().map(|change| change.changed_at)
+    } else {
+        None
+    };
+    PriceHistoryPage {
+        changes,
+        next_cursor,
+    }
+}
+
+// Function to convert an amount given in `unit` into a stock quantity of a product
+fn to_product_quantity(product_unit: Unit, amount: u32, unit: Option<Unit>) -> Result<u32, Error> {
+    let unit = unit.unwrap_or(product_unit);
+    let quantity = to_base_quantity(amount as u64, unit, product_unit)?;
+    u32::try_from(quantity).map_err(|_| Error::InvalidOperation {
+        msg: format!("Amount {} {} is too large", amount, unit.symbol()),
+    })
+}
+
+// Function to convert a price given for one `unit` into the price of one base
+// unit. Prices that don't divide evenly are rejected rather than rounded.
+fn to_base_price(price: u64, unit: Unit) -> Result<u64, Error> {
+    if !price.is_multiple_of(unit.factor()) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Price {} per {} does not divide into a whole price per {}",
+                price,
+                unit.symbol(),
+                unit.base().symbol()
+            ),
+        });
+    }
+    Ok(price / unit.factor())
+}
+
+// Helper function to convert the sale price and unit cost of a payload into
+// prices per base unit
+fn payload_base_prices(payload: &ProductPayload) -> Result<(u64, u64), Error> {
+    Ok((
+        to_base_price(payload.price, payload.unit)?,
+        to_base_price(payload.unit_cost, payload.unit)?,
+    ))
+}
+
+// Function to add a new product to the storage
+fn _add_product(product: ProductPayload) -> Result<Product, Error> {
+    ensure_role(Role::Manager)?;
+
+    // Validate payload before processing
+    validate_new_product_payload(&product)?;
+    let quantity = to_product_quantity(product.unit.base(), product.quantity, Some(product.unit))?;
+    let (price, unit_cost) = payload_base_prices(&product)?;
+
+    // Generate a unique ID for the product
+    let id = ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment id counter");
+
+    // Create a new Product instance
+    let mut item = Product {
+        id,
+        name: product.name,
+        category_id: product.category_id,
+        quantity,
+        unit: product.unit.base(),
+        price,
+        unit_cost,
+        currency: product.currency,
+        reorder_point: None,
+        reorder_quantity: 0,
+        created_at: time(),
+        updated_at: None,
+        archived_at: None,
+        version: 0,
+    };
+
+    // Insert the new product into storage
+    do_insert(&mut item);
+    create_batch(item.id, item.quantity, None, None, None);
+    record_movement(&item, item.quantity as i64, MovementReason::Initial);
+    record_price_change(&item);
+    Ok(item)
+}
+
+// Update endpoint of _add_product, replaying the stored result for a repeated idempotency key
+#[ic_cdk::update]
+fn add_product(product: ProductPayload, idempotency_key: Option<String>) -> Result<Product, Error> {
+    idempotent("add_product", idempotency_key, || {
+        audited(
+            "add_product",
+            None,
+            |product: &Product| Some(AuditTarget::Id(product.id)),
+            || _add_product(product),
+        )
+    })
+}
+
+// Function to update an existing product's details. When an expected version
+// is given, the update is rejected with a conflict if the product has changed since.
+fn _update_product(
+    id: u64,
+    payload: ProductPayload,
+    expected_version: Option<u64>,
+) -> Result<Product, Error> {
+    ensure_role(Role::Manager)?;
+
+    // Validate payload before processing
+    validate_product_payload(&payload)?;
+
+    // Update the product if it exists in storage
+    match STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut product) => {
+            ensure_active(&product)?;
+            check_version(&product, expected_version)?;
+            if payload.unit.dimension() != product.unit.dimension() {
+                return Err(Error::IncompatibleUnit {
+                    msg: format!(
+                        "Product with id={} is measured in {:?} and cannot switch to {:?}",
+                        id, product.unit, payload.unit
+                    ),
+                });
+            }
+            let quantity = to_product_quantity(product.unit, payload.quantity, Some(payload.unit))?;
+            let (price, unit_cost) = payload_base_prices(&payload)?;
+            let delta = quantity as i64 - product.quantity as i64;
+            let repriced = price_differs(&product, price, unit_cost, &payload.currency);
+            product.name = payload.name;
+            product.category_id = payload.category_id;
+            product.quantity = quantity;
+            product.price = price;
+            product.unit_cost = unit_cost;
+            product.currency = payload.currency;
+            product.updated_at = Some(time());
+            do_insert(&mut product);
+            if delta > 0 {
+                create_batch(product.id, delta as u32, None, None, None);
+            } else if delta < 0 {
+                consume_batches(product.id, delta.unsigned_abs() as u32);
+            }
+            if delta != 0 {
+                record_movement(&product, delta, MovementReason::Adjustment);
+            }
+            if repriced {
+                record_price_change(&product);
+            }
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't update a product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _update_product, replaying the stored result for a repeated idempotency key
+#[ic_cdk::update]
+fn update_product(
+    id: u64,
+    payload: ProductPayload,
+    expected_version: Option<u64>,
+    idempotency_key: Option<String>,
+) -> Result<Product, Error> {
+    let before = _get_product(&id).as_ref().map(audit_summary);
+    idempotent("update_product", idempotency_key, || {
+        audited(
+            "update_product",
+            before,
+            |_| Some(AuditTarget::Id(id)),
+            || _update_product(id, payload, expected_version),
+        )
+    })
+}
+
+// Helper function to check the size of a bulk update
+fn validate_bulk_size(rows: usize) -> Result<(), Error> {
+    if rows == 0 || rows > MAX_BULK_ROWS {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A bulk update must contain between 1 and {} rows.",
+                MAX_BULK_ROWS
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to turn the failures of a bulk update into a single error
+// naming every failed row
+fn bulk_error(failures: Vec<(usize, Error)>) -> Error {
+    let rows: Vec<String> = failures
+        .iter()
+        .map(|(row, error)| format!("row {}: {}", row, error.message()))
+        .collect();
+    Error::InvalidOperation {
+        msg: format!(
+            "{} of the rows failed, nothing was changed. {}",
+            failures.len(),
+            rows.join("; ")
+        ),
+    }
+}
+
+// Function to add many products at once. Every row is validated first and
+// either all products are added or none are. Products are returned in row order.
+fn _add_products(payloads: Vec<ProductPayload>) -> Result<Vec<Product>, Error> {
+    ensure_role(Role::Manager)?;
+    validate_bulk_size(payloads.len())?;
+
+    let failures: Vec<(usize, Error)> = payloads
+        .iter()
+        .enumerate()
+        .filter_map(|(row, payload)| {
+            validate_new_product_payload(payload)
+                .and_then(|_| {
+                    to_product_quantity(payload.unit.base(), payload.quantity, Some(payload.unit))
+                })
+                .and_then(|_| payload_base_prices(payload))
+                .err()
+                .map(|error| (row, error))
+        })
+        .collect();
+    if !failures.is_empty() {
+        return Err(bulk_error(failures));
+    }
+
+    payloads.into_iter().map(_add_product).collect()
+}
+
+// Update endpoint of _add_products, replaying the products of the original call
+// for a repeated idempotency key
+#[ic_cdk::update]
+fn add_products(
+    payloads: Vec<ProductPayload>,
+    idempotency_key: Option<String>,
+) -> Result<Vec<Product>, Error> {
+    let versions = idempotent("add_products", idempotency_key, || {
+        audited("add_products", None, |_| None, || _add_products(payloads))
+            .map(|products| product_versions(&products))
+    })?;
+    Ok(products_at_versions(versions))
+}
+
+// Function to apply many stock adjustments at once, each a product id and a
+// signed change in the product's unit. Rows are checked in order against the
+// stock left by the rows before them, and either all rows are applied or none
+// are. The product as of each row is returned in row order.
+fn _adjust_stock_batch(adjustments: Vec<(u64, i64)>) -> Result<Vec<Product>, Error> {
+    ensure_role(Role::Clerk)?;
+    validate_bulk_size(adjustments.len())?;
+
+    // Work out the resulting quantities on copies before touching storage
+    let mut working: BTreeMap<u64, Product> = BTreeMap::new();
+    let mut failures: Vec<(usize, Error)> = Vec::new();
+    let mut amounts: Vec<u32> = Vec::with_capacity(adjustments.len());
+    for (row, (product_id, delta)) in adjustments.iter().enumerate() {
+        let product = match working.get(product_id) {
+            Some(product) => Some(product.clone()),
+            None => _get_product(product_id),
+        };
+        let Some(mut product) = product else {
+            failures.push((
+                row,
+                Error::NotFound {
+                    msg: format!("Product with id={} not found", product_id),
+                },
+            ));
+            continue;
+        };
+        let Ok(amount) = u32::try_from(delta.unsigned_abs()) else {
+            failures.push((
+                row,
+                Error::InvalidOperation {
+                    msg: format!(
+                        "Adjustment {} of product id={} is larger than {}",
+                        delta,
+                        product_id,
+                        u32::MAX
+                    ),
+                },
+            ));
+            continue;
+        };
+        let checked = validate_stock_payload(&StockPayload {
+            amount,
+            ..Default::default()
+        })
+        .and_then(|_| ensure_active(&product));
+        if let Err(error) = checked {
+            failures.push((row, error));
+            continue;
+        }
+
+        if *delta > 0 {
+            match product.quantity.checked_add(amount) {
+                Some(quantity) => product.quantity = quantity,
+                None => {
+                    failures.push((
+                        row,
+                        Error::InvalidOperation {
+                            msg: format!(
+                                "Adding {} would overflow the quantity of product id={}",
+                                delta, product_id
+                            ),
+                        },
+                    ));
+                    continue;
+                }
+            }
+        } else {
+            // Stock held by reservations cannot be taken out
+            let available = product
+                .quantity
+                .saturating_sub(reserved_quantity(product.id));
+            if amount > available {
+                failures.push((
+                    row,
+                    Error::InvalidOperation {
+                        msg: format!(
+                            "Cannot take out {} of product id={}. Available: {}",
+                            amount, product_id, available
+                        ),
+                    },
+                ));
+                continue;
+            }
+            product.quantity -= amount;
+        }
+        working.insert(*product_id, product);
+        amounts.push(amount);
+    }
+    if !failures.is_empty() {
+        return Err(bulk_error(failures));
+    }
+
+    // Every row checks out, apply them in order
+    let now = time();
+    let mut results = Vec::with_capacity(adjustments.len());
+    for ((product_id, delta), amount) in adjustments.into_iter().zip(amounts) {
+        let mut product = _get_product(&product_id).expect("product was checked above");
+        if delta > 0 {
+            product.quantity += amount;
+        } else {
+            product.quantity -= amount;
+        }
+        product.updated_at = Some(now);
+        do_insert(&mut product);
+        if delta > 0 {
+            create_batch(product.id, amount, None, None, None);
+        } else {
+            consume_batches(product.id, amount);
+        }
+        let change = if delta > 0 {
+            amount as i64
+        } else {
+            -(amount as i64)
+        };
+        record_movement(&product, change, MovementReason::Adjustment);
+        results.push(product);
+    }
+    Ok(results)
+}
+
+// Update endpoint of _adjust_stock_batch, replaying the products of the original
+// call for a repeated idempotency key
+#[ic_cdk::update]
+fn adjust_stock_batch(
+    adjustments: Vec<(u64, i64)>,
+    idempotency_key: Option<String>,
+) -> Result<Vec<Product>, Error> {
+    let before = audit_summary(
+        &adjustments
+            .iter()
+            .filter_map(|(id, _)| _get_product(id))
+            .collect::<Vec<Product>>(),
+    );
+    let versions = idempotent("adjust_stock_batch", idempotency_key, || {
+        audited(
+            "adjust_stock_batch",
+            Some(before),
+            |_| None,
+            || _adjust_stock_batch(adjustments),
+        )
+        .map(|products| product_versions(&products))
+    })?;
+    Ok(products_at_versions(versions))
+}
+
+// A chunk of a CSV export along with the cursor to fetch the next chunk.
+// Only the first chunk starts with the header row.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct CsvChunk {
+    csv: String,
+    next_cursor: Option<u64>,
+}
+
+// Products created and updated by a CSV import, in row order, along with the
+// ids of archived products whose rows were skipped
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct CsvImportResult {
+    created: Vec<Product>,
+    updated: Vec<Product>,
+    skipped: Vec<u64>,
+}
+
+// Helper function to quote a CSV field when it contains a separator, a quote,
+// a line break or surrounding spaces
+fn csv_field(value: &str) -> String {
+    let needs_quotes = value.contains([',', '"', '\n', '\r']) || value.trim() != value;
+    if needs_quotes {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Helper function to format an optional value as a CSV field, empty when missing
+fn csv_optional(value: Option<impl ToString>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+// Helper function to append a row of fields to a CSV document
+fn push_csv_row(csv: &mut String, fields: &[String]) {
+    let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+    csv.push_str(&row.join(","));
+    csv.push_str("\r\n");
+}
+
+// Function to split a CSV document into records. Quoted fields may contain
+// separators, doubled quotes and line breaks. Each record comes with the line
+// number it starts on. Blank lines are skipped.
+fn parse_csv(text: &str) -> Result<Vec<(usize, Vec<String>)>, Error> {
+    let mut records = Vec::new();
+    let mut fields: Vec<String> = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                fields.push(std::mem::take(&mut field));
+                if fields.len() > 1 || !fields[0].is_empty() {
+                    records.push((record_line, std::mem::take(&mut fields)));
+                }
+                fields.clear();
+                line += 1;
+                record_line = line;
+            }
+            c => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    if quoted {
+        return Err(Error::InvalidOperation {
+            msg: format!("line {}: unterminated quoted field", record_line),
+        });
+    }
+    fields.push(field);
+    if fields.len() > 1 || !fields[0].is_empty() {
+        records.push((record_line, fields));
+    }
+    Ok(records)
+}
+
+// Helper function to list the fields of a product in PRODUCT_CSV_COLUMNS order
+fn product_csv_fields(product: &Product) -> [String; 14] {
+    [
+        product.id.to_string(),
+        product.name.clone(),
+        product.category_id.to_string(),
+        product.quantity.to_string(),
+        product.unit.symbol().to_string(),
+        product.price.to_string(),
+        product.unit_cost.to_string(),
+        product.currency.clone(),
+        csv_optional(product.reorder_point),
+        product.reorder_quantity.to_string(),
+        product.created_at.to_string(),
+        csv_optional(product.updated_at),
+        csv_optional(product.archived_at),
+        product.version.to_string(),
+    ]
+}
+
+// Query function to export products as CSV in id order, archived ones included,
+// one chunk at a time
+#[ic_cdk::query]
+fn export_products_csv(cursor: Option<u64>, limit: u32) -> CsvChunk {
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+    let limit = match limit {
+        0 => MAX_CSV_CHUNK_ROWS,
+        limit => limit.min(MAX_CSV_CHUNK_ROWS),
+    } as usize;
+    let products: Vec<Product> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(_, product)| product)
+            .take(limit + 1)
+            .collect()
+    });
+
+    let mut csv = String::new();
+    if cursor.is_none() {
+        push_csv_row(&mut csv, &PRODUCT_CSV_COLUMNS.map(String::from));
+    }
+    for product in products.iter().take(limit) {
+        push_csv_row(&mut csv, &product_csv_fields(product));
+    }
+    let next_cursor = if products.len() > limit {
+        products.get(limit - 1).map(|product| product.id)
+    } else {
+        None
+    };
+    CsvChunk { csv, next_cursor }
+}
+
+// Query function to export the stock movements made between two times
+// (inclusive) as CSV, one chunk at a time. The cursor is the id of the last
+// movement of the previous chunk.
+#[ic_cdk::query]
+fn export_movements_csv(from: u64, to: u64, cursor: Option<u64>, limit: u32) -> CsvChunk {
+    let limit = match limit {
+        0 => MAX_CSV_CHUNK_ROWS,
+        limit => limit.min(MAX_CSV_CHUNK_ROWS),
+    } as u64;
+
+    MOVEMENTS.with(|log| {
+        let log = log.borrow();
+        // Movements are appended in time order, so binary search for the first one in range
+        let start = match cursor {
+            Some(cursor) => match cursor.checked_add(1) {
+                Some(start) => start,
+                // Nothing can follow the largest possible cursor
+                None => return CsvChunk::default(),
+            },
+            None => {
+                let (mut low, mut high) = (0, log.len());
+                while low < high {
+                    let middle = low + (high - low) / 2;
+                    match log.get(middle) {
+                        Some(movement) if movement.timestamp < from => low = middle + 1,
+                        _ => high = middle,
+                    }
+                }
+                low
+            }
+        };
+
+        let mut csv = String::new();
+        if cursor.is_none() {
+            push_csv_row(&mut csv, &MOVEMENT_CSV_COLUMNS.map(String::from));
+        }
+        let mut next_cursor = None;
+        let mut id = start;
+        while let Some(movement) = log.get(id) {
+            if movement.timestamp > to {
+                break;
+            }
+            if id - start == limit {
+                next_cursor = Some(id - 1);
+                break;
+            }
+            push_csv_row(
+                &mut csv,
+                &[
+                    movement.id.to_string(),
+                    movement.product_id.to_string(),
+                    movement.delta.to_string(),
+                    format!("{:?}", movement.reason),
+                    movement.caller.to_text(),
+                    movement.timestamp.to_string(),
+                    movement.resulting_quantity.to_string(),
+                    csv_optional(movement.supplier_id),
+                ],
+            );
+            id += 1;
+        }
+        CsvChunk { csv, next_cursor }
+    })
+}
+
+// Helper function to read the id of the product a CSV row updates, if it has one
+fn csv_row_id(columns: &BTreeMap<String, usize>, row: &[String]) -> Result<Option<u64>, Error> {
+    let id = columns
+        .get("id")
+        .and_then(|index| row.get(*index))
+        .map(|value| value.trim())
+        .unwrap_or("");
+    match id {
+        "" => Ok(None),
+        id => id.parse().map(Some).map_err(|_| Error::InvalidOperation {
+            msg: format!("id '{}' is not a valid number", id),
+        }),
+    }
+}
+
+// Helper function to read one CSV row into a product payload and its reorder
+// policy. When the row updates an existing product, missing or empty columns
+// keep its stored values. New products need a name, category_id and quantity.
+fn product_from_csv_row(
+    columns: &BTreeMap<String, usize>,
+    row: &[String],
+    existing: Option<&Product>,
+) -> Result<(ProductPayload, ReorderPayload), Error> {
+    let field = |name: &str| -> &str {
+        columns
+            .get(name)
+            .and_then(|index| row.get(*index))
+            .map(|value| value.trim())
+            .unwrap_or("")
+    };
+    let number = |name: &str, stored: Option<u64>| -> Result<u64, Error> {
+        match (field(name), stored) {
+            ("", Some(stored)) => Ok(stored),
+            ("", None) => Err(Error::InvalidOperation {
+                msg: format!("{} is required for a new product", name),
+            }),
+            (text, _) => text.parse().map_err(|_| Error::InvalidOperation {
+                msg: format!("{} '{}' is not a valid number", name, text),
+            }),
+        }
+    };
+
+    // An omitted quantity keeps the stored one, which is in the product's own unit
+    let (quantity, unit) = match (field("quantity"), existing) {
+        ("", Some(product)) => (product.quantity as u64, product.unit),
+        _ => {
+            let unit = match (field("unit"), existing) {
+                ("", Some(product)) => product.unit,
+                ("", None) => Unit::Piece,
+                (text, _) => Unit::parse(text).ok_or(Error::InvalidOperation {
+                    msg: format!("unit '{}' is not a known unit", text),
+                })?,
+            };
+            (number("quantity", None)?, unit)
+        }
+    };
+    let quantity = u32::try_from(quantity).map_err(|_| Error::InvalidOperation {
+        msg: format!("quantity '{}' is too large", field("quantity")),
+    })?;
+    let name = match (field("name"), existing) {
+        ("", Some(product)) => product.name.clone(),
+        (name, _) => name.to_string(),
+    };
+    let currency = match (field("currency"), existing) {
+        ("", Some(product)) => product.currency.clone(),
+        ("", None) => "USD".to_string(),
+        (currency, _) => currency.to_string(),
+    };
+    // Prices are given per `unit`, so a kept price is scaled up from the stored
+    // price per base unit
+    let stored_price = |stored: Option<u64>| -> Result<u64, Error> {
+        stored
+            .unwrap_or(0)
+            .checked_mul(unit.factor())
+            .ok_or(Error::InvalidOperation {
+                msg: format!("the price per {} is too large", unit.symbol()),
+            })
+    };
+    let reorder_amount = |name: &str, stored: u32| -> Result<u32, Error> {
+        u32::try_from(number(name, Some(stored as u64))?).map_err(|_| Error::InvalidOperation {
+            msg: format!("{} '{}' is too large", name, field(name)),
+        })
+    };
+    let reorder_point = match (field("reorder_point"), existing) {
+        ("", Some(product)) => product.reorder_point,
+        ("", None) => None,
+        _ => Some(reorder_amount("reorder_point", 0)?),
+    };
+    let payload = ProductPayload {
+        name,
+        quantity,
+        unit,
+        category_id: number("category_id", existing.map(|product| product.category_id))?,
+        price: number(
+            "price",
+            Some(stored_price(existing.map(|product| product.price))?),
+        )?,
+        unit_cost: number(
+            "unit_cost",
+            Some(stored_price(existing.map(|product| product.unit_cost))?),
+        )?,
+        currency,
+    };
+    let reorder = ReorderPayload {
+        reorder_point,
+        reorder_quantity: reorder_amount(
+            "reorder_quantity",
+            existing.map_or(0, |product| product.reorder_quantity),
+        )?,
+    };
+    Ok((payload, reorder))
+}
+
+// Function to import products from CSV. The first row names the columns, which
+// may be any of those written by export_products_csv. Rows with an id update
+// that product and only change the columns they fill in, the reorder policy
+// included. Rows without one add a new product and need a name, category_id
+// and a quantity above zero. Rows of archived products are skipped, so an
+// export can be imported back as it is. Timestamps and versions are read-only
+// and ignored. Every row
+// is validated first and either all rows are applied or none are, with the
+// line number of each failed row reported.
+fn _import_products_csv(csv: String) -> Result<CsvImportResult, Error> {
+    ensure_role(Role::Manager)?;
+
+    // Spreadsheet programs often start the file with a byte order mark
+    let csv = csv.strip_prefix('\u{feff}').unwrap_or(&csv);
+    let mut records = parse_csv(csv)?.into_iter();
+    let (_, header) = records.next().ok_or(Error::InvalidOperation {
+        msg: "The CSV has no header row.".to_string(),
+    })?;
+    let columns: BTreeMap<String, usize> = header
+        .iter()
+        .enumerate()
+        .map(|(index, name)| (name.trim().to_lowercase(), index))
+        .collect();
+    let records: Vec<(usize, Vec<String>)> = records.collect();
+    if records.is_empty() || records.len() > MAX_CSV_IMPORT_ROWS {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A CSV import must contain between 1 and {} rows.",
+                MAX_CSV_IMPORT_ROWS
+            ),
+        });
+    }
+
+    // Validate every row the way add_product and update_product would
+    let mut rows: Vec<(Option<u64>, ProductPayload, ReorderPayload)> = Vec::new();
+    let mut result = CsvImportResult::default();
+    let mut failures: Vec<String> = Vec::new();
+    for (line, record) in &records {
+        let checked = csv_row_id(&columns, record).and_then(|id| {
+            let Some(id) = id else {
+                let (payload, reorder) = product_from_csv_row(&columns, record, None)?;
+                validate_new_product_payload(&payload)?;
+                validate_reorder_payload(&reorder)?;
+                to_product_quantity(payload.unit.base(), payload.quantity, Some(payload.unit))?;
+                payload_base_prices(&payload)?;
+                return Ok(Some((None, payload, reorder)));
+            };
+            if rows.iter().any(|(other, _, _)| *other == Some(id)) || result.skipped.contains(&id) {
+                return Err(Error::InvalidOperation {
+                    msg: format!("product id={} appears on more than one row", id),
+                });
+            }
+            let product = _get_product(&id).ok_or(Error::NotFound {
+                msg: format!("product with id={} not found", id),
+            })?;
+            if product.archived_at.is_some() {
+                result.skipped.push(id);
+                return Ok(None);
+            }
+            let (payload, reorder) = product_from_csv_row(&columns, record, Some(&product))?;
+            validate_product_payload(&payload)?;
+            validate_reorder_payload(&reorder)?;
+            if payload.unit.dimension() != product.unit.dimension() {
+                return Err(Error::IncompatibleUnit {
+                    msg: format!(
+                        "product with id={} is measured in {:?}, not {:?}",
+                        id, product.unit, payload.unit
+                    ),
+                });
+            }
+            to_product_quantity(product.unit, payload.quantity, Some(payload.unit))?;
+            payload_base_prices(&payload)?;
+            Ok(Some((Some(id), payload, reorder)))
+        });
+        match checked {
+            Ok(Some(row)) => rows.push(row),
+            Ok(None) => {}
+            Err(error) => failures.push(format!("line {}: {}", line, error.message())),
+        }
+    }
+    if !failures.is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "{} of the rows failed, nothing was imported. {}",
+                failures.len(),
+                failures.join("; ")
+            ),
+        });
+    }
+
+    for (id, payload, reorder) in rows {
+        let mut product = match id {
+            Some(id) => _update_product(id, payload, None)?,
+            None => _add_product(payload)?,
+        };
+        if product.reorder_point != reorder.reorder_point
+            || product.reorder_quantity != reorder.reorder_quantity
+        {
+            product = _set_reorder_policy(product.id, reorder)?;
+        }
+        match id {
+            Some(_) => result.updated.push(product),
+            None => result.created.push(product),
+        }
+    }
+    Ok(result)
+}
+
+// Update endpoint of _import_products_csv, replaying the products of the
+// original call for a repeated idempotency key
+#[ic_cdk::update]
+fn import_products_csv(
+    csv: String,
+    idempotency_key: Option<String>,
+) -> Result<CsvImportResult, Error> {
+    let (created, updated, skipped) = idempotent("import_products_csv", idempotency_key, || {
+        audited(
+            "import_products_csv",
+            None,
+            |_| None,
+            || _import_products_csv(csv),
+        )
+        .map(|result| {
+            (
+                product_versions(&result.created),
+                product_versions(&result.updated),
+                result.skipped,
+            )
+        })
+    })?;
+    Ok(CsvImportResult {
+        created: products_at_versions(created),
+        updated: products_at_versions(updated),
+        skipped,
+    })
+}
+
+// Function to change the sale price and unit cost of a product
+fn _set_price(id: u64, payload: PricePayload) -> Result<Product, Error> {
+    ensure_role(Role::Manager)?;
+
+    // Validate the price payload
+    validate_price_payload(&payload)?;
+
+    match STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut product) => {
+            ensure_active(&product)?;
+            if !price_differs(
+                &product,
+                payload.price,
+                payload.unit_cost,
+                &payload.currency,
+            ) {
+                return Ok(product);
+            }
+            product.price = payload.price;
+            product.unit_cost = payload.unit_cost;
+            product.currency = payload.currency;
+            product.updated_at = Some(time());
+            do_insert(&mut product);
+            record_price_change(&product);
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't set the price of product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _set_price, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn set_price(id: u64, payload: PricePayload) -> Result<Product, Error> {
+    audited(
+        "set_price",
+        _get_product(&id).as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _set_price(id, payload),
+    )
+}
+
+// Function to set the reorder point and reorder quantity of a product
+fn _set_reorder_policy(id: u64, payload: ReorderPayload) -> Result<Product, Error> {
+    ensure_role(Role::Manager)?;
+
+    // Validate the reorder payload
+    validate_reorder_payload(&payload)?;
+
+    match STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut product) => {
+            ensure_active(&product)?;
+            product.reorder_point = payload.reorder_point;
+            product.reorder_quantity = payload.reorder_quantity;
+            product.updated_at = Some(time());
+            do_insert(&mut product);
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't set the reorder policy of product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _set_reorder_policy, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn set_reorder_policy(id: u64, payload: ReorderPayload) -> Result<Product, Error> {
+    audited(
+        "set_reorder_policy",
+        _get_product(&id).as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _set_reorder_policy(id, payload),
+    )
+}
+
+// Helper function to check whether an active product's available stock is at
+// or below its reorder point
+fn is_low_stock(product: &Product) -> bool {
+    product.archived_at.is_none()
+        && product
+            .reorder_point
+            .is_some_and(|reorder_point| stock_level(product).available <= reorder_point)
+}
+
+// Query function to list every product at or below its reorder point, in id order
+#[ic_cdk::query]
+fn get_low_stock() -> Vec<Product> {
+    STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(is_low_stock)
+            .collect()
+    })
+}
+
+// Function to store a new batch of a product. Missing details default to a
+// generated lot code, baked now and no best before date.
+fn create_batch(
+    product_id: u64,
+    quantity: u32,
+    lot_code: Option<String>,
+    baked_at: Option<u64>,
+    best_before: Option<u64>,
+) -> Option<Batch> {
+    if quantity == 0 {
+        return None;
+    }
+
+    let id = BATCH_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment batch id counter");
+
+    let now = time();
+    let batch = Batch {
+        id,
+        product_id,
+        lot_code: lot_code.unwrap_or_else(|| format!("LOT-{}", id)),
+        quantity,
+        baked_at: baked_at.unwrap_or(now),
+        best_before,
+        created_at: now,
+    };
+    BATCHES.with(|batches| batches.borrow_mut().insert((product_id, id), batch.clone()));
+    Some(batch)
+}
+
+// Function to take stock out of a product's batches, earliest expiry first.
+// Batches without a best before date are used last, and emptied batches are removed.
+fn consume_batches(product_id: u64, quantity: u32) {
+    let mut batches = get_product_batches(product_id);
+    batches.sort_by_key(|batch| {
+        (
+            batch.best_before.unwrap_or(u64::MAX),
+            batch.baked_at,
+            batch.id,
+        )
+    });
+
+    let mut remaining = quantity;
+    BATCHES.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for mut batch in batches {
+            if remaining == 0 {
+                break;
+            }
+            let taken = remaining.min(batch.quantity);
+            remaining -= taken;
+            batch.quantity -= taken;
+            if batch.quantity == 0 {
+                storage.remove(&(product_id, batch.id));
+            } else {
+                storage.insert((product_id, batch.id), batch);
+            }
+        }
+    });
+}
+
+// Function to put stock that predates batch tracking into a batch of its own,
+// so that every product's batches add up to its quantity
+fn reconcile_batches() {
+    let products: Vec<Product> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .collect()
+    });
+    for product in products {
+        let batched = get_product_batches(product.id)
+            .iter()
+            .fold(0u32, |total, batch| total.saturating_add(batch.quantity));
+        if batched < product.quantity {
+            create_batch(
+                product.id,
+                product.quantity - batched,
+                Some("UNTRACKED".to_string()),
+                Some(product.created_at),
+                None,
+            );
+        }
+    }
+}
+
+// Query function to list the batches of a product
+#[ic_cdk::query]
+fn get_product_batches(product_id: u64) -> Vec<Batch> {
+    BATCHES.with(|batches| {
+        batches
+            .borrow()
+            .range((product_id, 0)..=(product_id, u64::MAX))
+            .map(|(_, batch)| batch)
+            .collect()
+    })
+}
+
+// Query function to list every batch whose best before date falls within the
+// given number of seconds from now, including batches already past it
+#[ic_cdk::query]
+fn get_expiring_batches(within_seconds: u64) -> Vec<Batch> {
+    let deadline = time().saturating_add(within_seconds.saturating_mul(1_000_000_000));
+    let mut expiring: Vec<Batch> = BATCHES.with(|batches| {
+        batches
+            .borrow()
+            .iter()
+            .map(|(_, batch)| batch)
+            .filter(|batch| {
+                batch
+                    .best_before
+                    .is_some_and(|best_before| best_before <= deadline)
+            })
+            .collect()
+    });
+    expiring.sort_by_key(|batch| (batch.best_before, batch.id));
+    expiring
+}
+
+// Function to add stock to a product's quantity, optionally only if the product
+// is still at the expected version
+fn _add_quantity(
+    id: u64,
+    payload: StockPayload,
+    expected_version: Option<u64>,
+) -> Result<Product, Error> {
+    ensure_role(Role::Clerk)?;
+
+    // Validate the stock payload
+    validate_stock_payload(&payload)?;
+
+    match STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut product) => {
+            ensure_active(&product)?;
+            check_version(&product, expected_version)?;
+            let amount = to_product_quantity(product.unit, payload.amount, payload.unit)?;
+            product.quantity =
+                product
+                    .quantity
+                    .checked_add(amount)
+                    .ok_or(Error::InvalidOperation {
+                        msg: format!(
+                            "Adding {} would overflow the quantity of product id={}",
+                            amount, id
+                        ),
+                    })?;
+            product.updated_at = Some(time());
+            do_insert(&mut product);
+            create_batch(
+                product.id,
+                amount,
+                payload.lot_code,
+                payload.baked_at,
+                payload.best_before,
+            );
+            record_movement(&product, amount as i64, MovementReason::Restock);
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't add quantity to product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _add_quantity, replaying the stored result for a repeated idempotency key
+#[ic_cdk::update]
+fn add_quantity(
+    id: u64,
+    payload: StockPayload,
+    expected_version: Option<u64>,
+    idempotency_key: Option<String>,
+) -> Result<Product, Error> {
+    let before = _get_product(&id).as_ref().map(audit_summary);
+    idempotent("add_quantity", idempotency_key, || {
+        audited(
+            "add_quantity",
+            before,
+            |_| Some(AuditTarget::Id(id)),
+            || _add_quantity(id, payload, expected_version),
+        )
+    })
+}
+
+// Function to remove stock from a product's quantity, optionally only if the
+// product is still at the expected version
+fn _offload_quantity(
+    id: u64,
+    payload: StockPayload,
+    expected_version: Option<u64>,
+) -> Result<Product, Error> {
+    ensure_role(Role::Clerk)?;
+
+    // Validate the stock payload
+    validate_stock_payload(&payload)?;
+
+    match STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut product) => {
+            ensure_active(&product)?;
+            check_version(&product, expected_version)?;
+            let amount = to_product_quantity(product.unit, payload.amount, payload.unit)?;
+
+            // Stock held by reservations cannot be offloaded
+            let available = stock_level(&product).available;
+            if product.quantity == 0 {
+                return Err(Error::InvalidOperation {
+                    msg: format!(
+                        "Product with id={} cannot be offloaded because the quantity is 0",
+                        id
+                    ),
+                });
+            } else if amount > available {
+                return Err(Error::InvalidOperation {
+                    msg: format!(
+                        "Cannot offload more than available quantity. Available: {}, Trying to offload: {}",
+                        available, amount
+                    ),
+                });
+            }
+            product.quantity -= amount;
+            product.updated_at = Some(time());
+            do_insert(&mut product);
+            consume_batches(product.id, amount);
+            record_movement(&product, -(amount as i64), MovementReason::Offload);
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't offload a product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _offload_quantity, replaying the stored result for a repeated idempotency key
+#[ic_cdk::update]
+fn offload_quantity(
+    id: u64,
+    payload: StockPayload,
+    expected_version: Option<u64>,
+    idempotency_key: Option<String>,
+) -> Result<Product, Error> {
+    let before = _get_product(&id).as_ref().map(audit_summary);
+    idempotent("offload_quantity", idempotency_key, || {
+        audited(
+            "offload_quantity",
+            before,
+            |_| Some(AuditTarget::Id(id)),
+            || _offload_quantity(id, payload, expected_version),
+        )
+    })
+}
+
+// Helper function to reject a write made against an outdated version of a product
+fn check_version(product: &Product, expected_version: Option<u64>) -> Result<(), Error> {
+    match expected_version {
+        Some(expected) if expected != product.version => Err(Error::Conflict {
+            msg: format!(
+                "Product with id={} is at version {}, not {}. Reload it and try again",
+                product.id, product.version, expected
+            ),
+            current_version: product.version,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Helper function to reject changes to an archived product
+fn ensure_active(product: &Product) -> Result<(), Error> {
+    match product.archived_at {
+        Some(_) => Err(Error::InvalidOperation {
+            msg: format!(
+                "Product with id={} is archived. Unarchive it first",
+                product.id
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Function to archive a product. Archived products keep their stock and history,
+// but are hidden from listings and reject stock changes until unarchived.
+fn _remove_product(id: u64) -> Result<Product, Error> {
+    ensure_role(Role::Manager)?;
+
+    match STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut product) => {
+            ensure_active(&product)?;
+            // Reservations of an archived product can never be fulfilled
+            for reservation in get_product_reservations(id) {
+                do_remove_reservation(&reservation.id);
+            }
+            product.archived_at = Some(time());
+            product.updated_at = product.archived_at;
+            do_insert(&mut product);
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't archive a product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _remove_product, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn remove_product(id: u64) -> Result<Product, Error> {
+    audited(
+        "remove_product",
+        _get_product(&id).as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _remove_product(id),
+    )
+}
+
+// Function to restore an archived product
+fn _unarchive_product(id: u64) -> Result<Product, Error> {
+    ensure_role(Role::Manager)?;
+
+    match STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut product) if product.archived_at.is_some() => {
+            product.archived_at = None;
+            product.updated_at = Some(time());
+            do_insert(&mut product);
+            Ok(product)
+        }
+        Some(_) => Err(Error::InvalidOperation {
+            msg: format!("Product with id={} is not archived", id),
+        }),
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't unarchive a product with id={}. Product not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _unarchive_product, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn unarchive_product(id: u64) -> Result<Product, Error> {
+    audited(
+        "unarchive_product",
+        _get_product(&id).as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _unarchive_product(id),
+    )
+}
+
+// Function to permanently delete an archived product along with its batches and recipe.
+// Its orders and stock movements are kept.
+fn _purge_product(id: u64) -> Result<Product, Error> {
+    ensure_role(Role::Owner)?;
+
+    match _get_product(&id) {
+        Some(product) if product.archived_at.is_none() => {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Product with id={} must be archived before it is purged",
+                    id
+                ),
+            });
+        }
+        Some(_) => {}
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("Couldn't purge a product with id={}. Product not found", id),
+            });
+        }
+    }
+
+    match do_remove(&id) {
+        Some(product) => {
+            for reservation in get_product_reservations(id) {
+                do_remove_reservation(&reservation.id);
+            }
+            for batch in get_product_batches(id) {
+                BATCHES.with(|batches| batches.borrow_mut().remove(&(id, batch.id)));
+            }
+            RECIPES.with(|recipes| recipes.borrow_mut().remove(&id));
+            remove_product_history(id);
+            if product.quantity > 0 {
+                let remaining = product.quantity as i64;
+                let emptied = Product {
+                    quantity: 0,
+                    ..product.clone()
+                };
+                record_movement(&emptied, -remaining, MovementReason::Removal);
+            }
+            Ok(product)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Couldn't purge a product with id={}. Product not found", id),
+        }),
+    }
+}
+
+// Update endpoint of _purge_product, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn purge_product(id: u64) -> Result<Product, Error> {
+    audited(
+        "purge_product",
+        _get_product(&id).as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _purge_product(id),
+    )
+}
+
+// Function to place an order. Availability is checked for every line before any
+// stock is touched, so either all quantities are decremented or none are.
+fn _place_order(payload: OrderPayload) -> Result<Order, Error> {
+    let placed_by = ensure_role(Role::Clerk)?;
+
+    // Validate the order payload
+    validate_order_payload(&payload)?;
+
+    // Sum the requested quantity per product, in case a product appears on several lines
+    let mut requested: BTreeMap<u64, u32> = BTreeMap::new();
+    for line in &payload.lines {
+        let total = requested.entry(line.product_id).or_insert(0);
+        *total = total
+            .checked_add(line.quantity)
+            .ok_or(Error::InvalidOperation {
+                msg: format!(
+                    "Order quantity for product id={} is too large",
+                    line.product_id
+                ),
+            })?;
+    }
+
+    // Stock held by the caller's reservations is available to this order
+    let mut held: BTreeMap<u64, u32> = BTreeMap::new();
+    let mut reservations: Vec<Reservation> = Vec::with_capacity(payload.reservation_ids.len());
+    for (index, reservation_id) in payload.reservation_ids.iter().enumerate() {
+        if payload.reservation_ids[..index].contains(reservation_id) {
+            return Err(Error::InvalidOperation {
+                msg: format!("Reservation id={} is listed more than once", reservation_id),
+            });
+        }
+        let reservation = _get_active_reservation(reservation_id)?;
+        ensure_reservation_owner(&reservation, placed_by)?;
+        if !requested.contains_key(&reservation.product_id) {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Reservation id={} is for product id={}, which is not part of the order",
+                    reservation_id, reservation.product_id
+                ),
+            });
+        }
+        let total = held.entry(reservation.product_id).or_insert(0);
+        *total = total.saturating_add(reservation.quantity);
+        reservations.push(reservation);
+    }
+
+    // Check availability and currency of every product before changing anything
+    let mut products: BTreeMap<u64, Product> = BTreeMap::new();
+    for (product_id, quantity) in &requested {
+        let product = _get_product(product_id).ok_or(Error::NotFound {
+            msg: format!(
+                "Couldn't place order. Product with id={} not found",
+                product_id
+            ),
+        })?;
+        ensure_active(&product)?;
+        let held = held.get(product_id).copied().unwrap_or(0);
+        let available = stock_level(&product)
+            .available
+            .saturating_add(held)
+            .min(product.quantity);
+        if available < *quantity {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Insufficient stock for product id={}. Available: {}, Ordered: {}",
+                    product_id, available, quantity
+                ),
+            });
+        }
+        products.insert(*product_id, product);
+    }
+    let currency = products
+        .values()
+        .next()
+        .map(|p| p.currency.clone())
+        .unwrap_or_default();
+    if products
+        .values()
+        .any(|product| product.currency != currency)
+    {
+        return Err(Error::InvalidOperation {
+            msg: "All products of an order must be priced in the same currency".to_string(),
+        });
+    }
+
+    let mut total: u64 = 0;
+    let mut lines = Vec::with_capacity(payload.lines.len());
+    for line in payload.lines {
+        let unit_price = products[&line.product_id].price;
+        total = (line.quantity as u64)
+            .checked_mul(unit_price)
+            .and_then(|amount| total.checked_add(amount))
+            .ok_or(Error::InvalidOperation {
+                msg: "Order total is too large".to_string(),
+            })?;
+        lines.push(OrderLine {
+            product_id: line.product_id,
+            quantity: line.quantity,
+            unit_price,
+        });
+    }
+
+    // Every line is available: take what the order needs from the reservations
+    // and decrement the stock of all products
+    let mut needed = requested.clone();
+    for mut reservation in reservations {
+        let need = needed
+            .get_mut(&reservation.product_id)
+            .expect("reservation product was checked above");
+        let used = reservation.quantity.min(*need);
+        *need -= used;
+        if used == reservation.quantity {
+            do_remove_reservation(&reservation.id);
+        } else {
+            reservation.quantity -= used;
+            RESERVATIONS.with(|reservations| {
+                reservations
+                    .borrow_mut()
+                    .insert(reservation.id, reservation)
+            });
+        }
+    }
+    for (product_id, quantity) in requested {
+        let mut product = products
+            .remove(&product_id)
+            .expect("product was checked above");
+        product.quantity -= quantity;
+        product.updated_at = Some(time());
+        do_insert(&mut product);
+        consume_batches(product.id, quantity);
+        record_movement(&product, -(quantity as i64), MovementReason::Sale);
+    }
+
+    let id = ORDER_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment order id counter");
+
+    let order = Order {
+        id,
+        lines,
+        status: OrderStatus::Pending,
+        total,
+        currency,
+        placed_by,
+        created_at: time(),
+        updated_at: None,
+        unreturned: None,
+    };
+    ORDERS.with(|orders| orders.borrow_mut().insert(order.id, order.clone()));
+    Ok(order)
+}
+
+// Update endpoint of _place_order, replaying the stored result for a repeated idempotency key
+#[ic_cdk::update]
+fn place_order(payload: OrderPayload, idempotency_key: Option<String>) -> Result<Order, Error> {
+    idempotent("place_order", idempotency_key, || {
+        audited(
+            "place_order",
+            None,
+            |order: &Order| Some(AuditTarget::Id(order.id)),
+            || _place_order(payload),
+        )
+    })
+}
+
+// Function to move an order to a new status. Allowed transitions are
+// Pending -> Paid -> Fulfilled, and Pending/Paid -> Cancelled, which puts the stock
+// back into products that are still active.
+fn _update_order_status(id: u64, status: OrderStatus) -> Result<Order, Error> {
+    ensure_role(Role::Clerk)?;
+
+    let mut order = ORDERS
+        .with(|orders| orders.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("Couldn't update an order with id={}. Order not found", id),
+        })?;
+
+    let allowed = matches!(
+        (order.status, status),
+        (OrderStatus::Pending, OrderStatus::Paid)
+            | (OrderStatus::Paid, OrderStatus::Fulfilled)
+            | (OrderStatus::Pending, OrderStatus::Cancelled)
+            | (OrderStatus::Paid, OrderStatus::Cancelled)
+    );
+    if !allowed {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Order with id={} cannot move from {:?} to {:?}",
+                id, order.status, status
+            ),
+        });
+    }
+
+    if status == OrderStatus::Cancelled {
+        // Return the stock of every line to products that are still active.
+        // Whatever cannot be returned is listed on the order.
+        let mut unreturned = Vec::new();
+        for line in &order.lines {
+            let returned = match _get_product(&line.product_id) {
+                Some(mut product) if product.archived_at.is_none() => {
+                    let returned = line.quantity.min(u32::MAX - product.quantity);
+                    if returned > 0 {
+                        product.quantity += returned;
+                        product.updated_at = Some(time());
+                        do_insert(&mut product);
+                        create_batch(product.id, returned, None, None, None);
+                        record_movement(&product, returned as i64, MovementReason::OrderCancelled);
+                    }
+                    returned
+                }
+                _ => 0,
+            };
+            if returned < line.quantity {
+                unreturned.push(UnreturnedStock {
+                    product_id: line.product_id,
+                    quantity: line.quantity - returned,
+                });
+            }
+        }
+        if !unreturned.is_empty() {
+            order.unreturned = Some(unreturned);
+        }
+    }
+
+    order.status = status;
+    order.updated_at = Some(time());
+    ORDERS.with(|orders| orders.borrow_mut().insert(order.id, order.clone()));
+    Ok(order)
+}
+
+// Update endpoint of _update_order_status, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn update_order_status(id: u64, status: OrderStatus) -> Result<Order, Error> {
+    audited(
+        "update_order_status",
+        get_order(id).ok().as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _update_order_status(id, status),
+    )
+}
+
+// Query function to retrieve an order by ID
+#[ic_cdk::query]
+fn get_order(id: u64) -> Result<Order, Error> {
+    match ORDERS.with(|orders| orders.borrow().get(&id)) {
+        Some(order) => Ok(order),
+        None => Err(Error::NotFound {
+            msg: format!("An order with id={} was not found", id),
+        }),
+    }
+}
+
+// Query function to list orders in id order, optionally only those with the given status
+#[ic_cdk::query]
+fn list_orders(cursor: Option<u64>, limit: u32, status: Option<OrderStatus>) -> OrderPage {
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+    let limit = page_limit(limit);
+    let mut orders: Vec<Order> = ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(_, order)| order)
+            .filter(|order| status.is_none_or(|status| order.status == status))
+            .take(limit + 1)
+            .collect()
+    });
+    let next_cursor = if orders.len() > limit {
+        orders.truncate(limit);
+        orders.last().map(|order| order.id)
+    } else {
+        None
+    };
+    OrderPage {
+        orders,
+        next_cursor,
+    }
+}
+
+// Helper function to retrieve a reservation that has not expired yet
+fn _get_active_reservation(id: &u64) -> Result<Reservation, Error> {
+    match RESERVATIONS.with(|reservations| reservations.borrow().get(id)) {
+        Some(reservation) if reservation.expires_at > time() => Ok(reservation),
+        Some(_) => Err(Error::InvalidOperation {
+            msg: format!("Reservation with id={} has expired", id),
+        }),
+        None => Err(Error::NotFound {
+            msg: format!("A reservation with id={} was not found", id),
+        }),
+    }
+}
+
+// Helper function to check that a reservation was made by the given principal.
+// Managers and owners may use or release any reservation.
+fn ensure_reservation_owner(reservation: &Reservation, principal: Principal) -> Result<(), Error> {
+    if reservation.created_by == principal || _get_role(&principal) >= Some(Role::Manager) {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: format!(
+            "Reservation with id={} belongs to {}",
+            reservation.id, reservation.created_by
+        ),
+    })
+}
+
+// Helper function to sum the quantity held by unexpired reservations of a product
+fn reserved_quantity(product_id: u64) -> u32 {
+    let now = time();
+    let ids: Vec<u64> = PRODUCT_RESERVATIONS.with(|index| {
+        index
+            .borrow()
+            .range((product_id, 0)..=(product_id, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    RESERVATIONS.with(|reservations| {
+        let reservations = reservations.borrow();
+        ids.iter()
+            .filter_map(|id| reservations.get(id))
+            .filter(|reservation| reservation.expires_at > now)
+            .fold(0u32, |total, reservation| {
+                total.saturating_add(reservation.quantity)
+            })
+    })
+}
+
+// Helper function to compute the stock level of a product
+fn stock_level(product: &Product) -> StockLevel {
+    let reserved = reserved_quantity(product.id);
+    StockLevel {
+        on_hand: product.quantity,
+        reserved,
+        available: product.quantity.saturating_sub(reserved),
+        unit: product.unit,
+    }
+}
+
+// Function to remove a reservation along with its index entry
+fn do_remove_reservation(id: &u64) -> Option<Reservation> {
+    let removed = RESERVATIONS.with(|reservations| reservations.borrow_mut().remove(id));
+    if let Some(reservation) = &removed {
+        PRODUCT_RESERVATIONS.with(|index| {
+            index
+                .borrow_mut()
+                .remove(&(reservation.product_id, reservation.id))
+        });
+    }
+    removed
+}
+
+// Function to release every reservation that has expired. Runs on a timer.
+fn release_expired_reservations() {
+    let now = time();
+    let expired: Vec<u64> = RESERVATIONS.with(|reservations| {
+        reservations
+            .borrow()
+            .iter()
+            .filter(|(_, reservation)| reservation.expires_at <= now)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for id in expired {
+        do_remove_reservation(&id);
+    }
+}
+
+// Function to hold stock of a product until the reservation expires
+fn _reserve_stock(payload: ReservationPayload) -> Result<Reservation, Error> {
+    let created_by = ensure_role(Role::Clerk)?;
+
+    // Validate the reservation payload
+    validate_reservation_payload(&payload)?;
+
+    let product = _get_product(&payload.product_id).ok_or(Error::NotFound {
+        msg: format!(
+            "Couldn't reserve product with id={}. Product not found",
+            payload.product_id
+        ),
+    })?;
+    ensure_active(&product)?;
+    let available = stock_level(&product).available;
+    if payload.quantity > available {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Cannot reserve more than available quantity. Available: {}, Trying to reserve: {}",
+                available, payload.quantity
+            ),
+        });
+    }
+
+    let id = RESERVATION_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment reservation id counter");
+
+    let now = time();
+    let reservation = Reservation {
+        id,
+        product_id: product.id,
+        quantity: payload.quantity,
+        created_by,
+        created_at: now,
+        expires_at: now + payload.ttl_seconds * 1_000_000_000,
+    };
+    RESERVATIONS.with(|reservations| {
+        reservations
+            .borrow_mut()
+            .insert(reservation.id, reservation.clone())
+    });
+    PRODUCT_RESERVATIONS.with(|index| index.borrow_mut().insert((product.id, id), ()));
+    Ok(reservation)
+}
+
+// Update endpoint of _reserve_stock, replaying the stored result for a repeated idempotency key
+#[ic_cdk::update]
+fn reserve_stock(
+    payload: ReservationPayload,
+    idempotency_key: Option<String>,
+) -> Result<Reservation, Error> {
+    idempotent("reserve_stock", idempotency_key, || {
+        audited(
+            "reserve_stock",
+            None,
+            |reservation: &Reservation| Some(AuditTarget::Id(reservation.id)),
+            || _reserve_stock(payload),
+        )
+    })
+}
+
+// Function to release a reservation before it expires. Clerks can only
+// release reservations they made.
+fn _release_reservation(id: u64) -> Result<Reservation, Error> {
+    let principal = ensure_role(Role::Clerk)?;
+
+    if let Ok(reservation) = get_reservation(id) {
+        ensure_reservation_owner(&reservation, principal)?;
+    }
+    match do_remove_reservation(&id) {
+        Some(reservation) => Ok(reservation),
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't release a reservation with id={}. Reservation not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _release_reservation, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn release_reservation(id: u64) -> Result<Reservation, Error> {
+    audited(
+        "release_reservation",
+        get_reservation(id).ok().as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _release_reservation(id),
+    )
+}
+
+// Query function to retrieve a reservation by ID
+#[ic_cdk::query]
+fn get_reservation(id: u64) -> Result<Reservation, Error> {
+    match RESERVATIONS.with(|reservations| reservations.borrow().get(&id)) {
+        Some(reservation) => Ok(reservation),
+        None => Err(Error::NotFound {
+            msg: format!("A reservation with id={} was not found", id),
+        }),
+    }
+}
+
+// Query function to list the reservations currently held against a product
+#[ic_cdk::query]
+fn get_product_reservations(product_id: u64) -> Vec<Reservation> {
+    let ids: Vec<u64> = PRODUCT_RESERVATIONS.with(|index| {
+        index
+            .borrow()
+            .range((product_id, 0)..=(product_id, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    RESERVATIONS.with(|reservations| {
+        let reservations = reservations.borrow();
+        ids.iter().filter_map(|id| reservations.get(id)).collect()
+    })
+}
+
+// Function to write off every batch past its best before date, taking its
+// quantity out of the product and recording it as waste. Runs on a timer.
+fn sweep_expired_batches() -> SweepResult {
+    let now = time();
+    let expired: Vec<Batch> = BATCHES.with(|batches| {
+        batches
+            .borrow()
+            .iter()
+            .map(|(_, batch)| batch)
+            .filter(|batch| {
+                batch
+                    .best_before
+                    .is_some_and(|best_before| best_before <= now)
+            })
+            .collect()
+    });
+
+    let mut result = SweepResult {
+        ran_at: now,
+        ..Default::default()
+    };
+    let mut affected: Vec<u64> = Vec::new();
+    for batch in expired {
+        BATCHES.with(|batches| batches.borrow_mut().remove(&(batch.product_id, batch.id)));
+
+        // Batches of removed products are dropped without touching any product
+        let Some(mut product) = _get_product(&batch.product_id) else {
+            continue;
+        };
+        let quantity = batch.quantity.min(product.quantity);
+        product.quantity -= quantity;
+        product.updated_at = Some(now);
+        do_insert(&mut product);
+        record_movement(&product, -(quantity as i64), MovementReason::Expired);
+
+        let id = WASTE_ID_COUNTER
+            .with(|counter| {
+                let current_value = *counter.borrow().get();
+                counter.borrow_mut().set(current_value + 1)
+            })
+            .expect("Cannot increment waste id counter");
+        let record = WasteRecord {
+            id,
+            product_id: batch.product_id,
+            batch_id: batch.id,
+            lot_code: batch.lot_code,
+            quantity,
+            reason: WasteReason::Expired,
+            recorded_at: now,
+        };
+        WASTE.with(|waste| waste.borrow_mut().insert(id, record));
+
+        result.batches_written_off += 1;
+        result.quantity_written_off += quantity as u64;
+        if !affected.contains(&batch.product_id) {
+            affected.push(batch.product_id);
+        }
+    }
+    result.products_affected = affected.len() as u32;
+
+    LAST_SWEEP
+        .with(|cell| cell.borrow_mut().set(result.clone()))
+        .expect("Cannot store the sweep result");
+    result
+}
+
+// Function to run the expiry sweep right away instead of waiting for the timer
+fn _run_expiry_sweep() -> Result<SweepResult, Error> {
+    ensure_role(Role::Manager)?;
+    Ok(sweep_expired_batches())
+}
+
+// Update endpoint of _run_expiry_sweep, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn run_expiry_sweep() -> Result<SweepResult, Error> {
+    audited("run_expiry_sweep", None, |_| None, _run_expiry_sweep)
+}
+
+// Query function to get the result of the most recent expiry sweep, if one has run
+#[ic_cdk::query]
+fn get_last_sweep() -> Option<SweepResult> {
+    let result = LAST_SWEEP.with(|cell| cell.borrow().get().clone());
+    if result.ran_at == 0 {
+        None
+    } else {
+        Some(result)
+    }
+}
+
+// Query function to page through the waste records, oldest first
+#[ic_cdk::query]
+fn list_waste_records(cursor: Option<u64>, limit: u32) -> WastePage {
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+    let limit = page_limit(limit);
+    let mut records: Vec<WasteRecord> = WASTE.with(|waste| {
+        waste
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(_, record)| record)
+            .take(limit + 1)
+            .collect()
+    });
+    let next_cursor = if records.len() > limit {
+        records.truncate(limit);
+        records.last().map(|record| record.id)
+    } else {
+        None
+    };
+    WastePage {
+        records,
+        next_cursor,
+    }
+}
+
+// Helper function to retrieve an ingredient by its ID
+fn _get_ingredient(id: &u64) -> Option<Ingredient> {
+    INGREDIENTS.with(|ingredients| ingredients.borrow().get(id))
+}
+
+// Function to insert an ingredient into the stable storage
+fn do_insert_ingredient(ingredient: &Ingredient) {
+    INGREDIENTS.with(|ingredients| {
+        ingredients
+            .borrow_mut()
+            .insert(ingredient.id, ingredient.clone())
+    });
+}
+
+// Function to add a new ingredient to the storage
+fn _add_ingredient(payload: IngredientPayload) -> Result<Ingredient, Error> {
+    ensure_role(Role::Manager)?;
+
+    // Validate payload before processing
+    validate_ingredient_payload(&payload)?;
+    let quantity = to_base_quantity(payload.quantity, payload.unit, payload.unit)?;
+
+    let id = INGREDIENT_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment ingredient id counter");
+
+    let ingredient = Ingredient {
+        id,
+        name: payload.name,
+        unit: payload.unit.base(),
+        quantity,
+        created_at: time(),
+        updated_at: None,
+    };
+    do_insert_ingredient(&ingredient);
+    Ok(ingredient)
+}
+
+// Update endpoint of _add_ingredient, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn add_ingredient(payload: IngredientPayload) -> Result<Ingredient, Error> {
+    audited(
+        "add_ingredient",
+        None,
+        |ingredient: &Ingredient| Some(AuditTarget::Id(ingredient.id)),
+        || _add_ingredient(payload),
+    )
+}
+
+// Function to update an existing ingredient's details
+fn _update_ingredient(id: u64, payload: IngredientPayload) -> Result<Ingredient, Error> {
+    ensure_role(Role::Manager)?;
+
+    // Validate payload before processing
+    validate_ingredient_payload(&payload)?;
+
+    match _get_ingredient(&id) {
+        Some(mut ingredient) => {
+            if payload.unit.dimension() != ingredient.unit.dimension() {
+                return Err(Error::IncompatibleUnit {
+                    msg: format!(
+                        "Ingredient with id={} is measured in {:?} and cannot switch to {:?}",
+                        id, ingredient.unit, payload.unit
+                    ),
+                });
+            }
+            ingredient.name = payload.name;
+            ingredient.quantity =
+                to_base_quantity(payload.quantity, payload.unit, ingredient.unit)?;
+            ingredient.updated_at = Some(time());
+            do_insert_ingredient(&ingredient);
+            Ok(ingredient)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't update an ingredient with id={}. Ingredient not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _update_ingredient, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn update_ingredient(id: u64, payload: IngredientPayload) -> Result<Ingredient, Error> {
+    audited(
+        "update_ingredient",
+        _get_ingredient(&id).as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _update_ingredient(id, payload),
+    )
+}
+
+// Function to add stock to an ingredient
+fn _restock_ingredient(id: u64, payload: IngredientStockPayload) -> Result<Ingredient, Error> {
+    ensure_role(Role::Clerk)?;
+
+    if payload.amount == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "Stock amount must be greater than zero.".to_string(),
+        });
+    }
+
+    match _get_ingredient(&id) {
+        Some(mut ingredient) => {
+            let amount = to_base_quantity(
+                payload.amount,
+                payload.unit.unwrap_or(ingredient.unit),
+                ingredient.unit,
+            )?;
+            ingredient.quantity =
+                ingredient
+                    .quantity
+                    .checked_add(amount)
+                    .ok_or(Error::InvalidOperation {
+                        msg: format!(
+                            "Adding {} would overflow the quantity of ingredient id={}",
+                            amount, id
+                        ),
+                    })?;
+            ingredient.updated_at = Some(time());
+            do_insert_ingredient(&ingredient);
+            Ok(ingredient)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't restock an ingredient with id={}. Ingredient not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _restock_ingredient, replaying the stored result for a repeated idempotency key
+#[ic_cdk::update]
+fn restock_ingredient(
+    id: u64,
+    payload: IngredientStockPayload,
+    idempotency_key: Option<String>,
+) -> Result<Ingredient, Error> {
+    let before = _get_ingredient(&id).as_ref().map(audit_summary);
+    idempotent("restock_ingredient", idempotency_key, || {
+        audited(
+            "restock_ingredient",
+            before,
+            |_| Some(AuditTarget::Id(id)),
+            || _restock_ingredient(id, payload),
+        )
+    })
+}
+
+// Function to remove an ingredient that no recipe uses anymore
+fn _remove_ingredient(id: u64) -> Result<Ingredient, Error> {
+    ensure_role(Role::Manager)?;
+
+    let used_by = RECIPES.with(|recipes| {
+        recipes
+            .borrow()
+            .iter()
+            .find(|(_, recipe)| recipe.lines.iter().any(|line| line.ingredient_id == id))
+            .map(|(product_id, _)| product_id)
+    });
+    if let Some(product_id) = used_by {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Ingredient with id={} is used by the recipe of product id={}",
+                id, product_id
+            ),
+        });
+    }
+
+    match INGREDIENTS.with(|ingredients| ingredients.borrow_mut().remove(&id)) {
+        Some(ingredient) => Ok(ingredient),
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't delete an ingredient with id={}. Ingredient not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _remove_ingredient, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn remove_ingredient(id: u64) -> Result<Ingredient, Error> {
+    audited(
+        "remove_ingredient",
+        _get_ingredient(&id).as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _remove_ingredient(id),
+    )
+}
+
+// Query function to retrieve an ingredient by ID
+#[ic_cdk::query]
+fn get_ingredient(id: u64) -> Result<Ingredient, Error> {
+    match _get_ingredient(&id) {
+        Some(ingredient) => Ok(ingredient),
+        None => Err(Error::NotFound {
+            msg: format!("An ingredient with id={} was not found", id),
+        }),
+    }
+}
+
+// Query function to list ingredients in id order, one page at a time
+#[ic_cdk::query]
+fn list_ingredients(cursor: Option<u64>, limit: u32) -> IngredientPage {
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+    let limit = page_limit(limit);
+    let mut ingredients: Vec<Ingredient> = INGREDIENTS.with(|ingredients| {
+        ingredients
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(_, ingredient)| ingredient)
+            .take(limit + 1)
+            .collect()
+    });
+    let next_cursor = if ingredients.len() > limit {
+        ingredients.truncate(limit);
+        ingredients.last().map(|ingredient| ingredient.id)
+    } else {
+        None
+    };
+    IngredientPage {
+        ingredients,
+        next_cursor,
+    }
+}
+
+// Function to set (or replace) the recipe of a product
+fn _set_recipe(product_id: u64, payload: RecipePayload) -> Result<Recipe, Error> {
+    ensure_role(Role::Manager)?;
+
+    // Validate payload before processing
+    validate_recipe_payload(&payload)?;
+
+    if _get_product(&product_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!(
+                "Couldn't set the recipe of product with id={}. Product not found",
+                product_id
+            ),
+        });
+    }
+    if let Some(line) = payload
+        .lines
+        .iter()
+        .find(|line| _get_ingredient(&line.ingredient_id).is_none())
+    {
+        return Err(Error::NotFound {
+            msg: format!("An ingredient with id={} was not found", line.ingredient_id),
+        });
+    }
+
+    let recipe = Recipe {
+        product_id,
+        lines: payload
+            .lines
+            .into_iter()
+            .map(|line| RecipeLine {
+                ingredient_id: line.ingredient_id,
+                quantity: line.quantity,
+            })
+            .collect(),
+        updated_at: time(),
+    };
+    RECIPES.with(|recipes| recipes.borrow_mut().insert(product_id, recipe.clone()));
+    Ok(recipe)
+}
+
+// Update endpoint of _set_recipe, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn set_recipe(product_id: u64, payload: RecipePayload) -> Result<Recipe, Error> {
+    audited(
+        "set_recipe",
+        get_recipe(product_id).ok().as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(product_id)),
+        || _set_recipe(product_id, payload),
+    )
+}
+
+// Query function to retrieve the recipe of a product
+#[ic_cdk::query]
+fn get_recipe(product_id: u64) -> Result<Recipe, Error> {
+    match RECIPES.with(|recipes| recipes.borrow().get(&product_id)) {
+        Some(recipe) => Ok(recipe),
+        None => Err(Error::NotFound {
+            msg: format!("Product with id={} has no recipe", product_id),
+        }),
+    }
+}
+
+// Function to make finished goods from their recipe: every ingredient is
+// checked first, then all are consumed and the product gets a new batch.
+fn _produce(product_id: u64, payload: ProducePayload) -> Result<Product, Error> {
+    ensure_role(Role::Clerk)?;
+
+    // Validate payload before processing
+    validate_produce_payload(&payload)?;
+
+    let mut product = _get_product(&product_id).ok_or(Error::NotFound {
+        msg: format!(
+            "Couldn't produce product with id={}. Product not found",
+            product_id
+        ),
+    })?;
+    ensure_active(&product)?;
+    let recipe = get_recipe(product_id)?;
+
+    // Work out what every ingredient must supply and collect all shortages
+    let mut consumed: Vec<(Ingredient, u64)> = Vec::with_capacity(recipe.lines.len());
+    let mut shortages: Vec<String> = Vec::new();
+    for line in &recipe.lines {
+        let ingredient = _get_ingredient(&line.ingredient_id).ok_or(Error::NotFound {
+            msg: format!("An ingredient with id={} was not found", line.ingredient_id),
+        })?;
+        let needed =
+            line.quantity
+                .checked_mul(payload.quantity as u64)
+                .ok_or(Error::InvalidOperation {
+                    msg: format!(
+                        "Producing {} would need too much of ingredient id={}",
+                        payload.quantity, ingredient.id
+                    ),
+                })?;
+        if ingredient.quantity < needed {
+            shortages.push(format!(
+                "{} (id={}): need {} {}, have {} {}",
+                ingredient.name,
+                ingredient.id,
+                needed,
+                ingredient.unit.symbol(),
+                ingredient.quantity,
+                ingredient.unit.symbol()
+            ));
+        }
+        consumed.push((ingredient, needed));
+    }
+    if !shortages.is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Not enough ingredients to produce {} of product id={}: {}",
+                payload.quantity,
+                product_id,
+                shortages.join("; ")
+            ),
+        });
+    }
+    product.quantity =
+        product
+            .quantity
+            .checked_add(payload.quantity)
+            .ok_or(Error::InvalidOperation {
+                msg: format!(
+                    "Producing {} would overflow the quantity of product id={}",
+                    payload.quantity, product_id
+                ),
+            })?;
+
+    // Everything is available: consume the ingredients and add the finished goods
+    let now = time();
+    for (mut ingredient, needed) in consumed {
+        ingredient.quantity -= needed;
+        ingredient.updated_at = Some(now);
+        do_insert_ingredient(&ingredient);
+    }
+    product.updated_at = Some(now);
+    do_insert(&mut product);
+    create_batch(
+        product.id,
+        payload.quantity,
+        payload.lot_code,
+        payload.baked_at,
+        payload.best_before,
+    );
+    record_movement(
+        &product,
+        payload.quantity as i64,
+        MovementReason::Production,
+    );
+    Ok(product)
+}
+
+// Update endpoint of _produce, replaying the stored result for a repeated idempotency key
+#[ic_cdk::update]
+fn produce(
+    product_id: u64,
+    payload: ProducePayload,
+    idempotency_key: Option<String>,
+) -> Result<Product, Error> {
+    let before = _get_product(&product_id).as_ref().map(audit_summary);
+    idempotent("produce", idempotency_key, || {
+        audited(
+            "produce",
+            before,
+            |_| Some(AuditTarget::Id(product_id)),
+            || _produce(product_id, payload),
+        )
+    })
+}
+
+// Helper function to retrieve a supplier by its ID
+fn _get_supplier(id: &u64) -> Option<Supplier> {
+    SUPPLIERS.with(|suppliers| suppliers.borrow().get(id))
+}
+
+// Helper function to check that the product or ingredient behind an item exists
+fn ensure_item_exists(item: &SuppliedItem) -> Result<(), Error> {
+    let exists = match item {
+        SuppliedItem::Product(id) => match _get_product(id) {
+            Some(product) => return ensure_active(&product),
+            None => false,
+        },
+        SuppliedItem::Ingredient(id) => _get_ingredient(id).is_some(),
+    };
+    if exists {
+        Ok(())
+    } else {
+        Err(Error::NotFound {
+            msg: format!("{:?} was not found", item),
+        })
+    }
+}
+
+// Function to add a new supplier to the registry
+fn _add_supplier(payload: SupplierPayload) -> Result<Supplier, Error> {
+    ensure_role(Role::Manager)?;
+
+    // Validate payload before processing
+    validate_supplier_payload(&payload)?;
+
+    let id = SUPPLIER_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment supplier id counter");
+
+    let supplier = Supplier {
+        id,
+        name: payload.name,
+        contact: payload.contact,
+        lead_time_days: payload.lead_time_days,
+        items: payload.items,
+        created_at: time(),
+        updated_at: None,
+    };
+    SUPPLIERS.with(|suppliers| suppliers.borrow_mut().insert(id, supplier.clone()));
+    Ok(supplier)
+}
+
+// Update endpoint of _add_supplier, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn add_supplier(payload: SupplierPayload) -> Result<Supplier, Error> {
+    audited(
+        "add_supplier",
+        None,
+        |supplier: &Supplier| Some(AuditTarget::Id(supplier.id)),
+        || _add_supplier(payload),
+    )
+}
+
+// Function to update an existing supplier's details
+fn _update_supplier(id: u64, payload: SupplierPayload) -> Result<Supplier, Error> {
+    ensure_role(Role::Manager)?;
+
+    // Validate payload before processing
+    validate_supplier_payload(&payload)?;
+
+    match _get_supplier(&id) {
+        Some(mut supplier) => {
+            supplier.name = payload.name;
+            supplier.contact = payload.contact;
+            supplier.lead_time_days = payload.lead_time_days;
+            supplier.items = payload.items;
+            supplier.updated_at = Some(time());
+            SUPPLIERS.with(|suppliers| suppliers.borrow_mut().insert(id, supplier.clone()));
+            Ok(supplier)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't update a supplier with id={}. Supplier not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _update_supplier, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn update_supplier(id: u64, payload: SupplierPayload) -> Result<Supplier, Error> {
+    audited(
+        "update_supplier",
+        _get_supplier(&id).as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _update_supplier(id, payload),
+    )
+}
+
+// Function to remove a supplier without open purchase orders
+fn _remove_supplier(id: u64) -> Result<Supplier, Error> {
+    ensure_role(Role::Manager)?;
+
+    let open_order = PURCHASE_ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .find(|order| {
+                order.supplier_id == id
+                    && !matches!(
+                        order.status,
+                        PurchaseOrderStatus::Received | PurchaseOrderStatus::Cancelled
+                    )
+            })
+    });
+    if let Some(order) = open_order {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Supplier with id={} has an open purchase order id={}",
+                id, order.id
+            ),
+        });
+    }
+
+    match SUPPLIERS.with(|suppliers| suppliers.borrow_mut().remove(&id)) {
+        Some(supplier) => Ok(supplier),
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Couldn't delete a supplier with id={}. Supplier not found",
+                id
+            ),
+        }),
+    }
+}
+
+// Update endpoint of _remove_supplier, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn remove_supplier(id: u64) -> Result<Supplier, Error> {
+    audited(
+        "remove_supplier",
+        _get_supplier(&id).as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _remove_supplier(id),
+    )
+}
+
+// Query function to retrieve a supplier by ID
+#[ic_cdk::query]
+fn get_supplier(id: u64) -> Result<Supplier, Error> {
+    match _get_supplier(&id) {
+        Some(supplier) => Ok(supplier),
+        None => Err(Error::NotFound {
+            msg: format!("A supplier with id={} was not found", id),
+        }),
+    }
+}
+
+// Query function to list suppliers in id order
+#[ic_cdk::query]
+fn list_suppliers(cursor: Option<u64>, limit: u32) -> SupplierPage {
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+    let limit = page_limit(limit);
+    let mut suppliers: Vec<Supplier> = SUPPLIERS.with(|suppliers| {
+        suppliers
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(_, supplier)| supplier)
+            .take(limit + 1)
+            .collect()
+    });
+    let next_cursor = if suppliers.len() > limit {
+        suppliers.truncate(limit);
+        suppliers.last().map(|supplier| supplier.id)
+    } else {
+        None
+    };
+    SupplierPage {
+        suppliers,
+        next_cursor,
+    }
+}
+
+// Function to draft a purchase order. Every line must be an item the supplier supplies.
+fn _create_purchase_order(payload: PurchaseOrderPayload) -> Result<PurchaseOrder, Error> {
+    let created_by = ensure_role(Role::Manager)?;
+
+    // Validate payload before processing
+    validate_purchase_order_payload(&payload)?;
+
+    let supplier = _get_supplier(&payload.supplier_id).ok_or(Error::NotFound {
+        msg: format!(
+            "Couldn't create a purchase order. Supplier with id={} not found",
+            payload.supplier_id
+        ),
+    })?;
+    for line in &payload.lines {
+        if !supplier.items.contains(&line.item) {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Supplier with id={} does not supply {:?}",
+                    supplier.id, line.item
+                ),
+            });
+        }
+        ensure_item_exists(&line.item)?;
+    }
+
+    let id = PURCHASE_ORDER_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment purchase order id counter");
+
+    let order = PurchaseOrder {
+        id,
+        supplier_id: supplier.id,
+        lines: payload
+            .lines
+            .into_iter()
+            .map(|line| PurchaseOrderLine {
+                item: line.item,
+                quantity: line.quantity,
+                received: 0,
+                unit_cost: line.unit_cost,
+            })
+            .collect(),
+        status: PurchaseOrderStatus::Draft,
+        created_by,
+        created_at: time(),
+        expected_at: None,
+        updated_at: None,
+    };
+    PURCHASE_ORDERS.with(|orders| orders.borrow_mut().insert(id, order.clone()));
+    Ok(order)
+}
+
+// Update endpoint of _create_purchase_order, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn create_purchase_order(payload: PurchaseOrderPayload) -> Result<PurchaseOrder, Error> {
+    audited(
+        "create_purchase_order",
+        None,
+        |order: &PurchaseOrder| Some(AuditTarget::Id(order.id)),
+        || _create_purchase_order(payload),
+    )
+}
+
+// Function to move a purchase order to a new status. Allowed transitions are
+// Draft -> Sent, and Draft/Sent/PartiallyReceived -> Cancelled. Orders become
+// (partially) received through receive_purchase_order.
+fn _update_purchase_order_status(
+    id: u64,
+    status: PurchaseOrderStatus,
+) -> Result<PurchaseOrder, Error> {
+    ensure_role(Role::Manager)?;
+
+    let mut order = PURCHASE_ORDERS
+        .with(|orders| orders.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Couldn't update a purchase order with id={}. Purchase order not found",
+                id
+            ),
+        })?;
+
+    let allowed = matches!(
+        (order.status, status),
+        (PurchaseOrderStatus::Draft, PurchaseOrderStatus::Sent)
+            | (PurchaseOrderStatus::Draft, PurchaseOrderStatus::Cancelled)
+            | (PurchaseOrderStatus::Sent, PurchaseOrderStatus::Cancelled)
+            | (
+                PurchaseOrderStatus::PartiallyReceived,
+                PurchaseOrderStatus::Cancelled
+            )
+    );
+    if !allowed {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Purchase order with id={} cannot move from {:?} to {:?}",
+                id, order.status, status
+            ),
+        });
+    }
+
+    let now = time();
+    if status == PurchaseOrderStatus::Sent {
+        let lead_time_days = _get_supplier(&order.supplier_id)
+            .map(|supplier| supplier.lead_time_days)
+            .unwrap_or(0);
+        // Suppliers stored before lead times were bounded may still have any value
+        let lead_time = (lead_time_days as u64).saturating_mul(24 * 60 * 60 * 1_000_000_000);
+        order.expected_at = Some(now.saturating_add(lead_time));
+    }
+    order.status = status;
+    order.updated_at = Some(now);
+    PURCHASE_ORDERS.with(|orders| orders.borrow_mut().insert(order.id, order.clone()));
+    Ok(order)
+}
+
+// Update endpoint of _update_purchase_order_status, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn update_purchase_order_status(
+    id: u64,
+    status: PurchaseOrderStatus,
+) -> Result<PurchaseOrder, Error> {
+    audited(
+        "update_purchase_order_status",
+        get_purchase_order(id).ok().as_ref().map(audit_summary),
+        |_| Some(AuditTarget::Id(id)),
+        || _update_purchase_order_status(id, status),
+    )
+}
+
+// Function to receive delivered stock for a sent purchase order. Every line is
+// checked before any stock is touched, so either all lines are received or none are.
+// Product stock is added as new batches and recorded against the supplier.
+fn _receive_purchase_order(id: u64, payload: ReceivePayload) -> Result<PurchaseOrder, Error> {
+    ensure_role(Role::Clerk)?;
+
+    let mut order = PURCHASE_ORDERS
+        .with(|orders| orders.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Couldn't receive a purchase order with id={}. Purchase order not found",
+                id
+            ),
+        })?;
+    if !matches!(
+        order.status,
+        PurchaseOrderStatus::Sent | PurchaseOrderStatus::PartiallyReceived
+    ) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Purchase order with id={} is {:?} and cannot be received",
+                id, order.status
+            ),
+        });
+    }
+    if payload.lines.is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: "At least one received line is required.".to_string(),
+        });
+    }
+
+    // Check every received line against what is still outstanding
+    let mut received: BTreeMap<usize, u64> = BTreeMap::new();
+    for received_line in &payload.lines {
+        let index = received_line.line as usize;
+        let line = order.lines.get(index).ok_or(Error::NotFound {
+            msg: format!(
+                "Purchase order with id={} has no line {}",
+                id, received_line.line
+            ),
+        })?;
+        if let SuppliedItem::Product(_) = line.item {
+            validate_stock_payload(&StockPayload {
+                amount: u32::try_from(received_line.quantity).unwrap_or(u32::MAX),
+                unit: None,
+                lot_code: received_line.lot_code.clone(),
+                baked_at: received_line.baked_at,
+                best_before: received_line.best_before,
+            })?;
+        } else if received_line.quantity == 0 {
+            return Err(Error::InvalidOperation {
+                msg: "Stock amount must be greater than zero.".to_string(),
+            });
+        }
+        let total = received.entry(index).or_insert(0);
+        *total = total.saturating_add(received_line.quantity);
+        let outstanding = line.quantity - line.received;
+        if *total > outstanding {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Line {} of purchase order id={} has {} outstanding, received {}",
+                    index, id, outstanding, total
+                ),
+            });
+        }
+    }
+
+    // Check that every item still exists and can take the extra stock
+    let mut products: BTreeMap<u64, Product> = BTreeMap::new();
+    let mut ingredients: BTreeMap<u64, Ingredient> = BTreeMap::new();
+    for (index, quantity) in &received {
+        match order.lines[*index].item {
+            SuppliedItem::Product(product_id) => {
+                let mut product = match products.remove(&product_id) {
+                    Some(product) => product,
+                    None => _get_product(&product_id).ok_or(Error::NotFound {
+                        msg: format!(
+                            "Couldn't receive line {}. Product with id={} not found",
+                            index, product_id
+                        ),
+                    })?,
+                };
+                ensure_active(&product)?;
+                product.quantity = u32::try_from(*quantity)
+                    .ok()
+                    .and_then(|quantity| product.quantity.checked_add(quantity))
+                    .ok_or(Error::InvalidOperation {
+                        msg: format!(
+                            "Receiving {} would overflow the quantity of product id={}",
+                            quantity, product_id
+                        ),
+                    })?;
+                products.insert(product_id, product);
+            }
+            SuppliedItem::Ingredient(ingredient_id) => {
+                let mut ingredient = match ingredients.remove(&ingredient_id) {
+                    Some(ingredient) => ingredient,
+                    None => _get_ingredient(&ingredient_id).ok_or(Error::NotFound {
+                        msg: format!(
+                            "Couldn't receive line {}. Ingredient with id={} not found",
+                            index, ingredient_id
+                        ),
+                    })?,
+                };
+                ingredient.quantity =
+                    ingredient
+                        .quantity
+                        .checked_add(*quantity)
+                        .ok_or(Error::InvalidOperation {
+                            msg: format!(
+                                "Receiving {} would overflow the quantity of ingredient id={}",
+                                quantity, ingredient_id
+                            ),
+                        })?;
+                ingredients.insert(ingredient_id, ingredient);
+            }
+        }
+    }
+
+    // Everything checks out, apply the receipt. Movements are recorded per line,
+    // so keep the running quantity of each product starting from its stored one.
+    let now = time();
+    let mut running: BTreeMap<u64, Product> = products
+        .keys()
+        .filter_map(|product_id| _get_product(product_id).map(|product| (*product_id, product)))
+        .collect();
+    for mut ingredient in ingredients.into_values() {
+        ingredient.updated_at = Some(now);
+        do_insert_ingredient(&ingredient);
+    }
+    for mut product in products.into_values() {
+        product.updated_at = Some(now);
+        do_insert(&mut product);
+    }
+    for received_line in payload.lines {
+        let line = &mut order.lines[received_line.line as usize];
+        line.received += received_line.quantity;
+        if let SuppliedItem::Product(product_id) = line.item {
+            let quantity = received_line.quantity as u32;
+            create_batch(
+                product_id,
+                quantity,
+                received_line.lot_code,
+                received_line.baked_at,
+                received_line.best_before,
+            );
+            if let Some(product) = running.get_mut(&product_id) {
+                product.quantity += quantity;
+                record_supplied_movement(
+                    product,
+                    quantity as i64,
+                    MovementReason::Restock,
+                    Some(order.supplier_id),
+                );
+            }
+        }
+    }
+
+    order.status = if order
+        .lines
+        .iter()
+        .all(|line| line.received == line.quantity)
+    {
+        PurchaseOrderStatus::Received
+    } else {
+        PurchaseOrderStatus::PartiallyReceived
+    };
+    order.updated_at = Some(now);
+    PURCHASE_ORDERS.with(|orders| orders.borrow_mut().insert(order.id, order.clone()));
+    Ok(order)
+}
+
+// Update endpoint of _receive_purchase_order, replaying the stored result for a
+// repeated idempotency key
+#[ic_cdk::update]
+fn receive_purchase_order(
+    id: u64,
+    payload: ReceivePayload,
+    idempotency_key: Option<String>,
+) -> Result<PurchaseOrder, Error> {
+    let before = get_purchase_order(id).ok().as_ref().map(audit_summary);
+    idempotent("receive_purchase_order", idempotency_key, || {
+        audited(
+            "receive_purchase_order",
+            before,
+            |_| Some(AuditTarget::Id(id)),
+            || _receive_purchase_order(id, payload),
+        )
+    })
+}
+
+// Query function to retrieve a purchase order by ID
+#[ic_cdk::query]
+fn get_purchase_order(id: u64) -> Result<PurchaseOrder, Error> {
+    match PURCHASE_ORDERS.with(|orders| orders.borrow().get(&id)) {
+        Some(order) => Ok(order),
+        None => Err(Error::NotFound {
+            msg: format!("A purchase order with id={} was not found", id),
+        }),
+    }
+}
+
+// Query function to list purchase orders in id order, optionally only those
+// of one supplier or with the given status
+#[ic_cdk::query]
+fn list_purchase_orders(
+    cursor: Option<u64>,
+    limit: u32,
+    supplier_id: Option<u64>,
+    status: Option<PurchaseOrderStatus>,
+) -> PurchaseOrderPage {
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+    let limit = page_limit(limit);
+    let mut purchase_orders: Vec<PurchaseOrder> = PURCHASE_ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(_, order)| order)
+            .filter(|order| supplier_id.is_none_or(|supplier_id| order.supplier_id == supplier_id))
+            .filter(|order| status.is_none_or(|status| order.status == status))
+            .take(limit + 1)
+            .collect()
+    });
+    let next_cursor = if purchase_orders.len() > limit {
+        purchase_orders.truncate(limit);
+        purchase_orders.last().map(|order| order.id)
+    } else {
+        None
+    };
+    PurchaseOrderPage {
+        purchase_orders,
+        next_cursor,
+    }
+}
+
+// Function to draft purchase orders for every low stock product that is not
+// already on an open purchase order. Each product is ordered from the supplier
+// with the shortest lead time, with one purchase order per supplier.
+fn draft_reorders(created_by: Principal) -> Vec<PurchaseOrder> {
+    let on_order: Vec<SuppliedItem> = PURCHASE_ORDERS.with(|orders| {
+        orders
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| {
+                matches!(
+                    order.status,
+                    PurchaseOrderStatus::Draft
+                        | PurchaseOrderStatus::Sent
+                        | PurchaseOrderStatus::PartiallyReceived
+                )
+            })
+            .flat_map(|order| order.lines.into_iter().map(|line| line.item))
+            .collect()
+    });
+    let suppliers: Vec<Supplier> = SUPPLIERS.with(|suppliers| {
+        suppliers
+            .borrow()
+            .iter()
+            .map(|(_, supplier)| supplier)
+            .collect()
+    });
+
+    let mut lines: BTreeMap<u64, Vec<PurchaseOrderLine>> = BTreeMap::new();
+    for product in get_low_stock() {
+        let item = SuppliedItem::Product(product.id);
+        if on_order.contains(&item) {
+            continue;
+        }
+        let supplier = suppliers
+            .iter()
+            .filter(|supplier| supplier.items.contains(&item))
+            .min_by_key(|supplier| supplier.lead_time_days);
+        if let Some(supplier) = supplier {
+            lines
+                .entry(supplier.id)
+                .or_default()
+                .push(PurchaseOrderLine {
+                    item,
+                    quantity: product.reorder_quantity as u64,
+                    received: 0,
+                    unit_cost: product.unit_cost,
+                });
+        }
+    }
+
+    let now = time();
+    let mut drafted = Vec::new();
+    for (supplier_id, lines) in lines {
+        for lines in lines.chunks(MAX_PURCHASE_ORDER_LINES) {
+            let id = PURCHASE_ORDER_ID_COUNTER
+                .with(|counter| {
+                    let current_value = *counter.borrow().get();
+                    counter.borrow_mut().set(current_value + 1)
+                })
+                .expect("Cannot increment purchase order id counter");
+            let order = PurchaseOrder {
+                id,
+                supplier_id,
+                lines: lines.to_vec(),
+                status: PurchaseOrderStatus::Draft,
+                created_by,
+                created_at: now,
+                expected_at: None,
+                updated_at: None,
+            };
+            PURCHASE_ORDERS.with(|orders| orders.borrow_mut().insert(id, order.clone()));
+            drafted.push(order);
+        }
+    }
+    drafted
+}
+
+// Function run by the reorder timer, drafting purchase orders when enabled
+fn run_scheduled_reorder() {
+    let mut settings = REORDER_SETTINGS.with(|cell| cell.borrow().get().clone());
+    if !settings.auto_draft {
+        return;
+    }
+    draft_reorders(ic_cdk::id());
+    settings.last_run_at = Some(time());
+    REORDER_SETTINGS
+        .with(|cell| cell.borrow_mut().set(settings))
+        .expect("Cannot store the reorder settings");
+}
+
+// Function to draft purchase orders for low stock right away
+fn _run_reorder() -> Result<Vec<PurchaseOrder>, Error> {
+    let created_by = ensure_role(Role::Manager)?;
+    Ok(draft_reorders(created_by))
+}
+
+// Update endpoint of _run_reorder, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn run_reorder() -> Result<Vec<PurchaseOrder>, Error> {
+    audited("run_reorder", None, |_| None, _run_reorder)
+}
+
+// Function to turn the automatic drafting of purchase orders on or off
+fn _set_auto_reorder(enabled: bool) -> Result<ReorderSettings, Error> {
+    ensure_role(Role::Manager)?;
+
+    let mut settings = REORDER_SETTINGS.with(|cell| cell.borrow().get().clone());
+    settings.auto_draft = enabled;
+    REORDER_SETTINGS
+        .with(|cell| cell.borrow_mut().set(settings.clone()))
+        .expect("Cannot store the reorder settings");
+    Ok(settings)
+}
+
+// Update endpoint of _set_auto_reorder, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn set_auto_reorder(enabled: bool) -> Result<ReorderSettings, Error> {
+    audited(
+        "set_auto_reorder",
+        Some(audit_summary(&get_reorder_settings())),
+        |_| None,
+        || _set_auto_reorder(enabled),
+    )
+}
+
+// Query function to retrieve the settings of the automatic reordering job
+#[ic_cdk::query]
+fn get_reorder_settings() -> ReorderSettings {
+    REORDER_SETTINGS.with(|cell| cell.borrow().get().clone())
+}
+
+// Function to run an update once per idempotency key of the caller. A retry with
+// a key seen within the retention window gets the stored result of the first
+// call instead of running again. Only successful results are stored, since a
+// failed call changes nothing and may safely be retried.
+fn idempotent<T>(
+    endpoint: &str,
+    key: Option<String>,
+    update: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error>
+where
+    T: candid::CandidType + for<'de> candid::Deserialize<'de>,
+{
+    let Some(key) = key else {
+        return update();
+    };
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Idempotency key must be between 1 and {} bytes.",
+                MAX_IDEMPOTENCY_KEY_LENGTH
+            ),
+        });
+    }
+
+    let storage_key = (StorablePrincipal(caller()), IdempotencyKey(key));
+    let retention = idempotency_retention_nanos();
+    let stored = IDEMPOTENCY.with(|records| records.borrow().get(&storage_key));
+    if let Some(record) = stored.filter(|record| record.created_at + retention > time()) {
+        if record.endpoint != endpoint {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Idempotency key '{}' was already used for {}",
+                    storage_key.1 .0, record.endpoint
+                ),
+            });
+        }
+        // A result stored before an upgrade changed its type cannot be replayed,
+        // and running the update again could apply it twice
+        return Decode!(&record.result, T).map_err(|_| Error::InvalidOperation {
+            msg: format!(
+                "The stored result for idempotency key '{}' can no longer be read. Check the state and retry with a new key",
+                storage_key.1 .0
+            ),
+        });
+    }
+
+    let result = update()?;
+    let record = IdempotencyRecord {
+        endpoint: endpoint.to_string(),
+        result: Encode!(&result).unwrap(),
+        created_at: time(),
+    };
+    IDEMPOTENCY.with(|records| records.borrow_mut().insert(storage_key, record));
+    Ok(result)
+}
+
+// Helper function to list the id and version of each product returned by a bulk
+// update. Bulk results are stored for idempotent replays in this form, as the
+// full products could outgrow an idempotency record.
+fn product_versions(products: &[Product]) -> Vec<(u64, u64)> {
+    products
+        .iter()
+        .map(|product| (product.id, product.version))
+        .collect()
+}
+
+// Helper function to look up products as they were at the given versions,
+// falling back to the stored product when a version is no longer in the history
+fn products_at_versions(versions: Vec<(u64, u64)>) -> Vec<Product> {
+    versions
+        .into_iter()
+        .filter_map(|key| {
+            PRODUCT_HISTORY
+                .with(|history| history.borrow().get(&key))
+                .map(|revision| revision.product)
+                .or_else(|| _get_product(&key.0))
+        })
+        .collect()
+}
+
+// Helper function to get the idempotency retention window in nanoseconds
+fn idempotency_retention_nanos() -> u64 {
+    let settings = IDEMPOTENCY_SETTINGS.with(|cell| cell.borrow().get().clone());
+    settings.retention_seconds * 1_000_000_000
+}
+
+// Function to forget idempotency keys older than the retention window
+fn purge_idempotency_records() {
+    let cutoff = time().saturating_sub(idempotency_retention_nanos());
+    IDEMPOTENCY.with(|records| {
+        let mut records = records.borrow_mut();
+        let expired: Vec<(StorablePrincipal, IdempotencyKey)> = records
+            .iter()
+            .filter(|(_, record)| record.created_at <= cutoff)
+            .map(|(key, _)| key)
+            .collect();
+        for key in expired {
+            records.remove(&key);
+        }
+    });
+}
+
+// Function to set how long idempotency keys are remembered
+fn _set_idempotency_retention(retention_seconds: u64) -> Result<IdempotencySettings, Error> {
+    ensure_role(Role::Owner)?;
+
+    if retention_seconds == 0 || retention_seconds > MAX_IDEMPOTENCY_RETENTION_SECONDS {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Retention must be between 1 and {} seconds.",
+                MAX_IDEMPOTENCY_RETENTION_SECONDS
+            ),
+        });
+    }
+    let settings = IdempotencySettings { retention_seconds };
+    IDEMPOTENCY_SETTINGS
+        .with(|cell| cell.borrow_mut().set(settings.clone()))
+        .expect("Cannot store the idempotency settings");
+    Ok(settings)
+}
+
+// Update endpoint of _set_idempotency_retention, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn set_idempotency_retention(retention_seconds: u64) -> Result<IdempotencySettings, Error> {
+    audited(
+        "set_idempotency_retention",
+        Some(audit_summary(&get_idempotency_settings())),
+        |_| None,
+        || _set_idempotency_retention(retention_seconds),
+    )
+}
+
+// Query function to retrieve the settings of the idempotency key store
+#[ic_cdk::query]
+fn get_idempotency_settings() -> IdempotencySettings {
+    IDEMPOTENCY_SETTINGS.with(|cell| cell.borrow().get().clone())
+}
+
+// Function to set how many versions of each product the history keeps. None
+// keeps every version. A lower limit drops the older versions of a product the
+// next time it changes.
+fn _set_product_history_retention(max_revisions: Option<u64>) -> Result<HistorySettings, Error> {
+    ensure_role(Role::Owner)?;
+
+    if max_revisions == Some(0) {
+        return Err(Error::InvalidOperation {
+            msg: "The history must keep at least one version.".to_string(),
+        });
+    }
+    let settings = HistorySettings { max_revisions };
+    HISTORY_SETTINGS
+        .with(|cell| cell.borrow_mut().set(settings.clone()))
+        .expect("Cannot store the history settings");
+    Ok(settings)
+}
+
+// Update endpoint of _set_product_history_retention, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn set_product_history_retention(max_revisions: Option<u64>) -> Result<HistorySettings, Error> {
+    audited(
+        "set_product_history_retention",
+        Some(audit_summary(&get_history_settings())),
+        |_| None,
+        || _set_product_history_retention(max_revisions),
+    )
+}
+
+// Query function to retrieve the settings of the product history
+#[ic_cdk::query]
+fn get_history_settings() -> HistorySettings {
+    HISTORY_SETTINGS.with(|cell| cell.borrow().get().clone())
+}
+
+// Helper function to summarise a record for the audit log as JSON. Summaries
+// longer than MAX_AUDIT_SUMMARY_LENGTH are cut on a character boundary and end
+// with AUDIT_TRUNCATED_MARKER, so they are not mistaken for complete JSON.
+fn audit_summary<T: serde::Serialize>(value: &T) -> String {
+    let mut summary = serde_json::to_string(value).unwrap_or_default();
+    if summary.len() > MAX_AUDIT_SUMMARY_LENGTH {
+        let mut end = MAX_AUDIT_SUMMARY_LENGTH - AUDIT_TRUNCATED_MARKER.len();
+        while !summary.is_char_boundary(end) {
+            end -= 1;
+        }
+        summary.truncate(end);
+        summary.push_str(AUDIT_TRUNCATED_MARKER);
+    }
+    summary
+}
+
+// Function to record a call in the audit log
+fn record_audit(method: &str, target: Option<AuditTarget>, before: Option<String>, after: String) {
+    append_audit_entry(AuditEntry {
+        id: 0,
+        caller: caller(),
+        method: method.to_string(),
+        target,
+        before,
+        after,
+        timestamp: time(),
+    });
+}
+
+// Function to give an audit entry the next id and append it to the audit log,
+// dropping the oldest entries beyond MAX_AUDIT_ENTRIES
+fn append_audit_entry(mut entry: AuditEntry) {
+    entry.id = AUDIT_ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .expect("Cannot increment audit id counter");
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        log.insert(entry.id, entry);
+        while log.len() > MAX_AUDIT_ENTRIES {
+            match log.first_key_value() {
+                Some((oldest, _)) => log.remove(&oldest),
+                None => break,
+            };
+        }
+    });
+}
+
+// Function to run an update and record it in the audit log when it succeeds.
+// `target` names the changed record, given the result so that calls creating
+// a record can point at its new id.
+fn audited<T: serde::Serialize>(
+    method: &str,
+    before: Option<String>,
+    target: impl FnOnce(&T) -> Option<AuditTarget>,
+    update: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let result = update()?;
+    record_audit(method, target(&result), before, audit_summary(&result));
+    Ok(result)
+}
+
+// Query function to page through the audit log, oldest first, keeping only the
+// entries matching the filter
+#[ic_cdk::query]
+fn list_audit_entries(
+    filter: AuditFilter,
+    cursor: Option<u64>,
+    limit: u32,
+) -> Result<AuditPage, Error> {
+    ensure_role(Role::Owner)?;
+
+    let start = match cursor {
+        Some(cursor) => Bound::Excluded(cursor),
+        None => Bound::Unbounded,
+    };
+    let limit = page_limit(limit);
+    let mut entries: Vec<AuditEntry> = AUDIT_LOG.with(|log| {
+        log.borrow()
+            .range((start, Bound::Unbounded))
+            .map(|(_, entry)| entry)
+            .filter(|entry| filter.caller.is_none_or(|caller| entry.caller == caller))
+            .filter(|entry| {
+                filter
+                    .method
+                    .as_ref()
+                    .is_none_or(|method| entry.method == *method)
+            })
+            .filter(|entry| filter.from.is_none_or(|from| entry.timestamp >= from))
+            // Entries are appended in time order, so nothing later can match
+            .take_while(|entry| filter.to.is_none_or(|to| entry.timestamp <= to))
+            .take(limit + 1)
+            .collect()
+    });
+    let next_cursor = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+    Ok(AuditPage {
+        entries,
+        next_cursor,
+    })
+}
+
+// Function to start the periodic background jobs. Timers do not survive
+// upgrades, so this runs from both init and post_upgrade.
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(RESERVATION_SWEEP_INTERVAL, release_expired_reservations);
+    ic_cdk_timers::set_timer_interval(EXPIRY_SWEEP_INTERVAL, || {
+        sweep_expired_batches();
+    });
+    ic_cdk_timers::set_timer_interval(REORDER_INTERVAL, run_scheduled_reorder);
+    ic_cdk_timers::set_timer_interval(IDEMPOTENCY_SWEEP_INTERVAL, purge_idempotency_records);
+}
+
+// Request received from the HTTP gateway
+#[derive(candid::CandidType, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// Response returned to the HTTP gateway
+#[derive(candid::CandidType, Serialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// Helper function to build a JSON response
+fn json_response(status_code: u16, body: &impl serde::Serialize) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: serde_json::to_vec(body).expect("Cannot encode the response as JSON"),
+    }
+}
+
+// Helper function to turn a query result into a JSON response, with the status
+// code of the error when it failed
+fn json_result(result: Result<impl serde::Serialize, Error>) -> HttpResponse {
+    match result {
+        Ok(body) => json_response(200, &body),
+        Err(error) => json_response(error.status_code(), &error),
+    }
+}
+
+// Helper function to parse an optional numeric query string parameter
+fn query_param<T: std::str::FromStr>(
+    params: &BTreeMap<&str, &str>,
+    name: &str,
+) -> Result<Option<T>, Error> {
+    match params.get(name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::InvalidOperation {
+                msg: format!(
+                    "Query parameter '{}' has an invalid value '{}'",
+                    name, value
+                ),
+            }),
+        None => Ok(None),
+    }
+}
+
+// Function to list products for GET /products. Takes the cursor, limit,
+// category_id and include_archived query string parameters.
+fn http_list_products(params: &BTreeMap<&str, &str>) -> Result<ProductPage, Error> {
+    list_products(ListProductsPayload {
+        cursor: query_param(params, "cursor")?,
+        limit: query_param(params, "limit")?.unwrap_or(0),
+        category_id: query_param(params, "category_id")?,
+        include_archived: query_param(params, "include_archived")?.unwrap_or(false),
+        ..Default::default()
+    })
+}
+
+// Query function serving read-only inventory data as JSON over the HTTP gateway:
+// GET /products, /products/{id}, /products/{id}/stock and /stock/low.
+// Responses are not certified, so they are only served through the raw domain.
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return json_response(
+            405,
+            &Error::InvalidOperation {
+                msg: format!("Method {} is not allowed", request.method),
+            },
+        );
+    }
+
+    let (path, query) = request
+        .url
+        .split_once('?')
+        .unwrap_or((request.url.as_str(), ""));
+    let params: BTreeMap<&str, &str> = query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .collect();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    let parse_id = |id: &str| {
+        id.parse::<u64>().map_err(|_| Error::InvalidOperation {
+            msg: format!("'{}' is not a valid product id", id),
+        })
+    };
+    match segments.as_slice() {
+        ["products"] => json_result(http_list_products(&params)),
+        ["products", id] => json_result(parse_id(id).and_then(get_product)),
+        ["products", id, "stock"] => json_result(parse_id(id).and_then(get_stock)),
+        ["stock", "low"] => json_response(200, &get_low_stock()),
+        _ => json_response(
+            404,
+            &Error::NotFound {
+                msg: format!("No route for {}", path),
+            },
+        ),
+    }
+}
+
+// Function to grant a role to a principal, replacing any role it already has
+fn _grant_role(principal: Principal, role: Role) -> Result<RoleAssignment, Error> {
+    ensure_role(Role::Owner)?;
+
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidOperation {
+            msg: "Roles cannot be granted to the anonymous principal".to_string(),
+        });
+    }
+    if _get_role(&principal) == Some(Role::Owner) && role != Role::Owner && owner_count() == 1 {
+        return Err(Error::InvalidOperation {
+            msg: "Cannot demote the last owner".to_string(),
+        });
+    }
+
+    ROLES.with(|roles| {
+        roles
+            .borrow_mut()
+            .insert(StorablePrincipal(principal), role)
+    });
+    Ok(RoleAssignment { principal, role })
+}
+
+// Update endpoint of _grant_role, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn grant_role(principal: Principal, role: Role) -> Result<RoleAssignment, Error> {
+    audited(
+        "grant_role",
+        _get_role(&principal).as_ref().map(|role| {
+            audit_summary(&RoleAssignment {
+                principal,
+                role: *role,
+            })
+        }),
+        |_| Some(AuditTarget::Principal(principal)),
+        || _grant_role(principal, role),
+    )
+}
+
+// Function to revoke the role of a principal
+fn _revoke_role(principal: Principal) -> Result<RoleAssignment, Error> {
+    ensure_role(Role::Owner)?;
+
+    match _get_role(&principal) {
+        Some(Role::Owner) if owner_count() == 1 => Err(Error::InvalidOperation {
+            msg: "Cannot revoke the role of the last owner".to_string(),
+        }),
+        Some(role) => {
+            ROLES.with(|roles| roles.borrow_mut().remove(&StorablePrincipal(principal)));
+            Ok(RoleAssignment { principal, role })
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Principal {} has no role", principal),
+        }),
+    }
+}
+
+// Update endpoint of _revoke_role, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn revoke_role(principal: Principal) -> Result<RoleAssignment, Error> {
+    audited(
+        "revoke_role",
+        _get_role(&principal).as_ref().map(|role| {
+            audit_summary(&RoleAssignment {
+                principal,
+                role: *role,
+            })
+        }),
+        |_| Some(AuditTarget::Principal(principal)),
+        || _revoke_role(principal),
+    )
+}
+
+// Query function to list every role assignment
+#[ic_cdk::query]
+fn list_roles() -> Result<Vec<RoleAssignment>, Error> {
+    ensure_role(Role::Owner)?;
+
+    Ok(ROLES.with(|roles| {
+        roles
+            .borrow()
+            .iter()
+            .map(|(principal, role)| RoleAssignment {
+                principal: principal.0,
+                role,
+            })
+            .collect()
+    }))
+}
+
+// Query function returning the role of the caller, if any
+#[ic_cdk::query]
+fn get_my_role() -> Option<Role> {
+    _get_role(&caller())
+}
+
+// Description of a backup snapshot, used to download it and to check it on restore
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Debug)]
+struct BackupManifest {
+    schema_version: u8,
+    total_bytes: u64,
+    chunk_size: u32,
+    chunk_count: u32,
+    // Hex encoded SHA-256 of the whole snapshot
+    checksum: String,
+    created_at: u64,
+}
+
+// Every stable structure of the canister, stored as the raw bytes of its entries
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Snapshot {
+    schema_version: u8,
+    sections: Vec<SnapshotSection>,
+}
+
+// The entries of one stable structure. Cells and logs have empty keys.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SnapshotSection {
+    name: String,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+// Helper function to copy the entries of a stable map into a snapshot section
+fn dump_map<K, V>(name: &str, map: &StableBTreeMap<K, V, Memory>) -> SnapshotSection
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    SnapshotSection {
+        name: name.to_string(),
+        entries: map
+            .iter()
+            .map(|(key, value)| (key.to_bytes().into_owned(), value.to_bytes().into_owned()))
+            .collect(),
+    }
+}
+
+// Helper function to copy the value of a stable cell into a snapshot section
+fn dump_cell<T: Storable>(name: &str, cell: &Cell<T, Memory>) -> SnapshotSection {
+    SnapshotSection {
+        name: name.to_string(),
+        entries: vec![(Vec::new(), cell.get().to_bytes().into_owned())],
+    }
+}
+
+// Helper function to copy the entries of a stable log into a snapshot section
+fn dump_log<T: Storable>(name: &str, log: &StableLog<T, Memory, Memory>) -> SnapshotSection {
+    SnapshotSection {
+        name: name.to_string(),
+        entries: (0..log.len())
+            .filter_map(|index| log.get(index))
+            .map(|entry| (Vec::new(), entry.to_bytes().into_owned()))
+            .collect(),
+    }
+}
+
+// Helper function to replace the entries of a stable map with those of a snapshot section
+fn load_map<K, V>(section: &SnapshotSection, map: &mut StableBTreeMap<K, V, Memory>)
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let stale: Vec<K> = map.iter().map(|(key, _)| key).collect();
+    for key in stale {
+        map.remove(&key);
+    }
+    for (key, value) in &section.entries {
+        map.insert(
+            K::from_bytes(Cow::Borrowed(key)),
+            V::from_bytes(Cow::Borrowed(value)),
+        );
+    }
+}
+
+// Helper function to set a stable cell to the value of a snapshot section
+fn load_cell<T: Storable>(section: &SnapshotSection, cell: &mut Cell<T, Memory>) {
+    if let Some((_, value)) = section.entries.first() {
+        cell.set(T::from_bytes(Cow::Borrowed(value)))
+            .expect("Cannot restore a cell");
+    }
+}
+
+// Helper function to append the entries of a snapshot section to an empty stable log
+fn load_log<T: Storable>(section: &SnapshotSection, log: &StableLog<T, Memory, Memory>) {
+    for (_, value) in &section.entries {
+        log.append(&T::from_bytes(Cow::Borrowed(value)))
+            .expect("Cannot restore a log entry");
+    }
+}
+
+// Function to capture every stable structure. Idempotency keys are left out,
+// since they only matter to retries against this canister.
+fn take_snapshot() -> Snapshot {
+    let sections = vec![
+        STORED_SCHEMA_VERSION.with(|cell| dump_cell("schema_version", &cell.borrow())),
+        ID_COUNTER.with(|cell| dump_cell("product_id_counter", &cell.borrow())),
+        STORAGE.with(|map| dump_map("products", &map.borrow())),
+        CATEGORY_INDEX.with(|map| dump_map("category_index", &map.borrow())),
+        ROLES.with(|map| dump_map("roles", &map.borrow())),
+        MOVEMENTS.with(|log| dump_log("movements", &log.borrow())),
+        PRODUCT_MOVEMENTS.with(|map| dump_map("product_movements", &map.borrow())),
+        PRICE_HISTORY.with(|map| dump_map("price_history", &map.borrow())),
+        ORDER_ID_COUNTER.with(|cell| dump_cell("order_id_counter", &cell.borrow())),
+        ORDERS.with(|map| dump_map("orders", &map.borrow())),
+        RESERVATION_ID_COUNTER.with(|cell| dump_cell("reservation_id_counter", &cell.borrow())),
+        RESERVATIONS.with(|map| dump_map("reservations", &map.borrow())),
+        PRODUCT_RESERVATIONS.with(|map| dump_map("product_reservations", &map.borrow())),
+        BATCH_ID_COUNTER.with(|cell| dump_cell("batch_id_counter", &cell.borrow())),
+        BATCHES.with(|map| dump_map("batches", &map.borrow())),
+        WASTE_ID_COUNTER.with(|cell| dump_cell("waste_id_counter", &cell.borrow())),
+        WASTE.with(|map| dump_map("waste", &map.borrow())),
+        LAST_SWEEP.with(|cell| dump_cell("last_sweep", &cell.borrow())),
+        INGREDIENT_ID_COUNTER.with(|cell| dump_cell("ingredient_id_counter", &cell.borrow())),
+        INGREDIENTS.with(|map| dump_map("ingredients", &map.borrow())),
+        RECIPES.with(|map| dump_map("recipes", &map.borrow())),
+        SUPPLIER_ID_COUNTER.with(|cell| dump_cell("supplier_id_counter", &cell.borrow())),
+        SUPPLIERS.with(|map| dump_map("suppliers", &map.borrow())),
+        PURCHASE_ORDER_ID_COUNTER
+            .with(|cell| dump_cell("purchase_order_id_counter", &cell.borrow())),
+        PURCHASE_ORDERS.with(|map| dump_map("purchase_orders", &map.borrow())),
+        REORDER_SETTINGS.with(|cell| dump_cell("reorder_settings", &cell.borrow())),
+        CATEGORY_ID_COUNTER.with(|cell| dump_cell("category_id_counter", &cell.borrow())),
+        CATEGORIES.with(|map| dump_map("categories", &map.borrow())),
+        SEARCH_INDEX.with(|map| dump_map("search_index", &map.borrow())),
+        IDEMPOTENCY_SETTINGS.with(|cell| dump_cell("idempotency_settings", &cell.borrow())),
+        AUDIT_ID_COUNTER.with(|cell| dump_cell("audit_id_counter", &cell.borrow())),
+        AUDIT_LOG.with(|map| dump_map("audit_log", &map.borrow())),
+        PRODUCT_HISTORY.with(|map| dump_map("product_history", &map.borrow())),
+        PRODUCT_HISTORY_TIMES.with(|map| dump_map("product_history_times", &map.borrow())),
+        HISTORY_SETTINGS.with(|cell| dump_cell("history_settings", &cell.borrow())),
+    ];
+    Snapshot {
+        schema_version: SCHEMA_VERSION,
+        sections,
+    }
+}
+
+// Function to write a snapshot into the stable structures. Roles are merged
+// into the current ones, so the restoring owner keeps access, and the audit
+// entries of the restore itself are kept.
+fn apply_snapshot(snapshot: &Snapshot) -> Result<(), Error> {
+    let sections: BTreeMap<&str, &SnapshotSection> = snapshot
+        .sections
+        .iter()
+        .map(|section| (section.name.as_str(), section))
+        .collect();
+    if let Some(missing) = SNAPSHOT_SECTIONS
+        .iter()
+        .find(|name| !sections.contains_key(*name))
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!("The snapshot has no '{}' section", missing),
+        });
+    }
+    let section = |name: &str| sections[name];
+
+    ID_COUNTER.with(|cell| load_cell(section("product_id_counter"), &mut cell.borrow_mut()));
+    STORAGE.with(|map| load_map(section("products"), &mut map.borrow_mut()));
+    CATEGORY_INDEX.with(|map| load_map(section("category_index"), &mut map.borrow_mut()));
+    ROLES.with(|map| {
+        let mut map = map.borrow_mut();
+        for (key, value) in &section("roles").entries {
+            map.insert(
+                StorablePrincipal::from_bytes(Cow::Borrowed(key)),
+                Role::from_bytes(Cow::Borrowed(value)),
+            );
+        }
+    });
+    MOVEMENTS.with(|log| load_log(section("movements"), &log.borrow()));
+    PRODUCT_MOVEMENTS.with(|map| load_map(section("product_movements"), &mut map.borrow_mut()));
+    PRICE_HISTORY.with(|map| load_map(section("price_history"), &mut map.borrow_mut()));
+    ORDER_ID_COUNTER.with(|cell| load_cell(section("order_id_counter"), &mut cell.borrow_mut()));
+    ORDERS.with(|map| load_map(section("orders"), &mut map.borrow_mut()));
+    RESERVATION_ID_COUNTER
+        .with(|cell| load_cell(section("reservation_id_counter"), &mut cell.borrow_mut()));
+    RESERVATIONS.with(|map| load_map(section("reservations"), &mut map.borrow_mut()));
+    PRODUCT_RESERVATIONS
+        .with(|map| load_map(section("product_reservations"), &mut map.borrow_mut()));
+    BATCH_ID_COUNTER.with(|cell| load_cell(section("batch_id_counter"), &mut cell.borrow_mut()));
+    BATCHES.with(|map| load_map(section("batches"), &mut map.borrow_mut()));
+    WASTE_ID_COUNTER.with(|cell| load_cell(section("waste_id_counter"), &mut cell.borrow_mut()));
+    WASTE.with(|map| load_map(section("waste"), &mut map.borrow_mut()));
+    LAST_SWEEP.with(|cell| load_cell(section("last_sweep"), &mut cell.borrow_mut()));
+    INGREDIENT_ID_COUNTER
+        .with(|cell| load_cell(section("ingredient_id_counter"), &mut cell.borrow_mut()));
+    INGREDIENTS.with(|map| load_map(section("ingredients"), &mut map.borrow_mut()));
+    RECIPES.with(|map| load_map(section("recipes"), &mut map.borrow_mut()));
+    SUPPLIER_ID_COUNTER
+        .with(|cell| load_cell(section("supplier_id_counter"), &mut cell.borrow_mut()));
+    SUPPLIERS.with(|map| load_map(section("suppliers"), &mut map.borrow_mut()));
+    PURCHASE_ORDER_ID_COUNTER
+        .with(|cell| load_cell(section("purchase_order_id_counter"), &mut cell.borrow_mut()));
+    PURCHASE_ORDERS.with(|map| load_map(section("purchase_orders"), &mut map.borrow_mut()));
+    REORDER_SETTINGS.with(|cell| load_cell(section("reorder_settings"), &mut cell.borrow_mut()));
+    CATEGORY_ID_COUNTER
+        .with(|cell| load_cell(section("category_id_counter"), &mut cell.borrow_mut()));
+    CATEGORIES.with(|map| load_map(section("categories"), &mut map.borrow_mut()));
+    SEARCH_INDEX.with(|map| load_map(section("search_index"), &mut map.borrow_mut()));
+    IDEMPOTENCY_SETTINGS
+        .with(|cell| load_cell(section("idempotency_settings"), &mut cell.borrow_mut()));
+    // The entries of the calls that set up this restore go after the restored ones
+    let setup: Vec<AuditEntry> =
+        AUDIT_LOG.with(|log| log.borrow().iter().map(|(_, entry)| entry).collect());
+    AUDIT_ID_COUNTER.with(|cell| load_cell(section("audit_id_counter"), &mut cell.borrow_mut()));
+    AUDIT_LOG.with(|map| load_map(section("audit_log"), &mut map.borrow_mut()));
+    for entry in setup {
+        append_audit_entry(entry);
+    }
+    PRODUCT_HISTORY.with(|map| load_map(section("product_history"), &mut map.borrow_mut()));
+    PRODUCT_HISTORY_TIMES
+        .with(|map| load_map(section("product_history_times"), &mut map.borrow_mut()));
+    HISTORY_SETTINGS.with(|cell| load_cell(section("history_settings"), &mut cell.borrow_mut()));
+    Ok(())
+}
+
+// Helper function to check whether the canister holds no inventory data yet.
+// Seeded categories, roles, settings and the audit entries of setting them do not count.
+fn is_empty_canister() -> bool {
+    STORAGE.with(|map| map.borrow().is_empty())
+        && MOVEMENTS.with(|log| log.borrow().is_empty())
+        && ORDERS.with(|map| map.borrow().is_empty())
+        && RESERVATIONS.with(|map| map.borrow().is_empty())
+        && BATCHES.with(|map| map.borrow().is_empty())
+        && WASTE.with(|map| map.borrow().is_empty())
+        && INGREDIENTS.with(|map| map.borrow().is_empty())
+        && SUPPLIERS.with(|map| map.borrow().is_empty())
+        && PURCHASE_ORDERS.with(|map| map.borrow().is_empty())
+        && PRICE_HISTORY.with(|map| map.borrow().is_empty())
+        && PRODUCT_HISTORY.with(|map| map.borrow().is_empty())
+        && AUDIT_LOG.with(|log| {
+            log.borrow()
+                .iter()
+                .all(|(_, entry)| RESTORE_SETUP_METHODS.contains(&entry.method.as_str()))
+        })
+}
+
+// Helper function to hash a snapshot
+fn snapshot_checksum(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+// Function to take a consistent snapshot of every stable structure and keep it
+// for download with get_backup_chunk. Replaces any earlier backup. The snapshot
+// is built in one call and must not exceed MAX_BACKUP_SIZE bytes. The backup is
+// kept on the heap, so an upgrade discards it and it has to be created again.
+fn _create_backup() -> Result<BackupManifest, Error> {
+    ensure_role(Role::Owner)?;
+
+    let bytes = Encode!(&take_snapshot()).unwrap();
+    if bytes.len() > MAX_BACKUP_SIZE {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The snapshot is {} bytes, more than the {} bytes that can be restored in one call",
+                bytes.len(),
+                MAX_BACKUP_SIZE
+            ),
+        });
+    }
+    let manifest = BackupManifest {
+        schema_version: SCHEMA_VERSION,
+        total_bytes: bytes.len() as u64,
+        chunk_size: MAX_BACKUP_CHUNK_SIZE as u32,
+        chunk_count: bytes.len().div_ceil(MAX_BACKUP_CHUNK_SIZE) as u32,
+        checksum: snapshot_checksum(&bytes),
+        created_at: time(),
+    };
+    BACKUP.with(|backup| *backup.borrow_mut() = Some((manifest.clone(), bytes)));
+    Ok(manifest)
+}
+
+// Update endpoint of _create_backup, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn create_backup() -> Result<BackupManifest, Error> {
+    audited("create_backup", None, |_| None, _create_backup)
+}
+
+// Query function to download one chunk of the backup made by create_backup
+#[ic_cdk::query]
+fn get_backup_chunk(index: u32) -> Result<Vec<u8>, Error> {
+    ensure_role(Role::Owner)?;
+
+    BACKUP.with(|backup| match &*backup.borrow() {
+        Some((manifest, bytes)) if index < manifest.chunk_count => {
+            let start = index as usize * MAX_BACKUP_CHUNK_SIZE;
+            let end = (start + MAX_BACKUP_CHUNK_SIZE).min(bytes.len());
+            Ok(bytes[start..end].to_vec())
+        }
+        Some((manifest, _)) => Err(Error::NotFound {
+            msg: format!(
+                "The backup has {} chunks, there is no chunk {}",
+                manifest.chunk_count, index
+            ),
+        }),
+        None => Err(Error::NotFound {
+            msg: "There is no backup. Call create_backup first".to_string(),
+        }),
+    })
+}
+
+// Function to start restoring a backup into a canister without inventory data.
+// The snapshot must come from the same schema version as this code and be at
+// most MAX_BACKUP_SIZE bytes. Uploaded chunks are kept on the heap, so an
+// upgrade before finish_restore means starting the restore again.
+fn _start_restore(manifest: BackupManifest) -> Result<(), Error> {
+    ensure_role(Role::Owner)?;
+
+    if !is_empty_canister() {
+        return Err(Error::InvalidOperation {
+            msg: "A backup can only be restored into a canister without inventory data".to_string(),
+        });
+    }
+    if manifest.schema_version != SCHEMA_VERSION {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The backup has schema version {}, this canister runs version {}",
+                manifest.schema_version, SCHEMA_VERSION
+            ),
+        });
+    }
+    if manifest.chunk_size as usize > MAX_BACKUP_CHUNK_SIZE || manifest.chunk_size == 0 {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Chunk size must be between 1 and {} bytes.",
+                MAX_BACKUP_CHUNK_SIZE
+            ),
+        });
+    }
+    if manifest.total_bytes > MAX_BACKUP_SIZE as u64 {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The backup is {} bytes, more than the {} bytes that can be restored in one call",
+                manifest.total_bytes, MAX_BACKUP_SIZE
+            ),
+        });
+    }
+    if (manifest.total_bytes as usize).div_ceil(manifest.chunk_size as usize)
+        != manifest.chunk_count as usize
+    {
+        return Err(Error::InvalidOperation {
+            msg: "The chunk count does not match the backup size".to_string(),
+        });
+    }
+    RESTORE.with(|restore| *restore.borrow_mut() = Some((manifest, Vec::new())));
+    Ok(())
+}
+
+// Update endpoint of _start_restore, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn start_restore(manifest: BackupManifest) -> Result<(), Error> {
+    audited("start_restore", None, |_| None, || _start_restore(manifest))
+}
+
+// Function to upload the next chunk of a backup being restored. Chunks must be
+// sent in order. Returns the number of chunks received so far.
+fn _upload_restore_chunk(index: u32, chunk: Vec<u8>) -> Result<u32, Error> {
+    ensure_role(Role::Owner)?;
+
+    RESTORE.with(|restore| match &mut *restore.borrow_mut() {
+        Some((manifest, bytes)) => {
+            let received = bytes.len().div_ceil(manifest.chunk_size as usize) as u32;
+            if index != received {
+                return Err(Error::InvalidOperation {
+                    msg: format!("Expected chunk {}, got chunk {}", received, index),
+                });
+            }
+            let last = index + 1 == manifest.chunk_count;
+            let expected_size = if last {
+                (manifest.total_bytes as usize).saturating_sub(bytes.len())
+            } else {
+                manifest.chunk_size as usize
+            };
+            if index >= manifest.chunk_count || chunk.len() != expected_size {
+                return Err(Error::InvalidOperation {
+                    msg: format!("Chunk {} does not match the backup manifest", index),
+                });
+            }
+            bytes.extend(chunk);
+            Ok(index + 1)
+        }
+        None => Err(Error::InvalidOperation {
+            msg: "No restore in progress. Call start_restore first".to_string(),
+        }),
+    })
+}
+
+// Update endpoint of _upload_restore_chunk, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn upload_restore_chunk(index: u32, chunk: Vec<u8>) -> Result<u32, Error> {
+    audited(
+        "upload_restore_chunk",
+        None,
+        |_| None,
+        || _upload_restore_chunk(index, chunk),
+    )
+}
+
+// Function to check the uploaded backup against its manifest and write it into
+// the stable structures
+fn _finish_restore() -> Result<BackupManifest, Error> {
+    ensure_role(Role::Owner)?;
+
+    let (manifest, bytes) =
+        RESTORE
+            .with(|restore| restore.borrow_mut().take())
+            .ok_or(Error::InvalidOperation {
+                msg: "No restore in progress. Call start_restore first".to_string(),
+            })?;
+    if bytes.len() as u64 != manifest.total_bytes {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Received {} of {} bytes. Start the restore again",
+                bytes.len(),
+                manifest.total_bytes
+            ),
+        });
+    }
+    if snapshot_checksum(&bytes) != manifest.checksum {
+        return Err(Error::InvalidOperation {
+            msg: "The backup does not match its checksum. Start the restore again".to_string(),
+        });
+    }
+    if !is_empty_canister() {
+        return Err(Error::InvalidOperation {
+            msg: "A backup can only be restored into a canister without inventory data".to_string(),
+        });
+    }
+    let snapshot = Decode!(&bytes, Snapshot).map_err(|e| Error::InvalidOperation {
+        msg: format!("Cannot decode the backup: {}", e),
+    })?;
+    if snapshot.schema_version != SCHEMA_VERSION {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The backup has schema version {}, this canister runs version {}",
+                snapshot.schema_version, SCHEMA_VERSION
+            ),
+        });
+    }
+    apply_snapshot(&snapshot)?;
+    Ok(manifest)
+}
+
+// Update endpoint of _finish_restore, recording an audit entry when it succeeds
+#[ic_cdk::update]
+fn finish_restore() -> Result<BackupManifest, Error> {
+    audited("finish_restore", None, |_| None, _finish_restore)
+}
+
+// Function to rewrite every stored product in the current layout. Each product is
+// decoded (migrating older layouts) and validated first, and the upgrade is
+// aborted if any of them is invalid, leaving the previous code in place.
+fn migrate_products() {
+    let stored_version = STORED_SCHEMA_VERSION.with(|cell| *cell.borrow().get());
+    if stored_version > SCHEMA_VERSION {
+        ic_cdk::trap(&format!(
+            "Stored schema version {} is newer than this code's version {}",
+            stored_version, SCHEMA_VERSION
+        ));
+    }
+    if stored_version == SCHEMA_VERSION {
+        return;
+    }
+
+    let products: Vec<(u64, Product)> = STORAGE.with(|service| service.borrow().iter().collect());
+    for (id, product) in &products {
+        if product.id != *id {
+            ic_cdk::trap(&format!(
+                "Product stored under id={} has id={}",
+                id, product.id
+            ));
+        }
+        if product.name.trim().is_empty() {
+            ic_cdk::trap(&format!("Product with id={} has an empty name", id));
+        }
+        if validate_currency(&product.currency).is_err() {
+            ic_cdk::trap(&format!(
+                "Product with id={} has an invalid currency code '{}'",
+                id, product.currency
+            ));
+        }
+    }
+    STORAGE.with(|service| {
+        let mut service = service.borrow_mut();
+        for (id, product) in products {
+            service.insert(id, product);
+        }
+    });
+    if stored_version < 3 {
+        migrate_ingredient_units();
+    }
+    if stored_version < 5 {
+        seed_categories();
+    }
+    if stored_version < 8 {
+        seed_product_history();
+    }
+
+    set_stored_schema_version();
+}
+
+// Function to move ingredients of schema version 2 onto base units. Quantities
+// in recipes using a scaled ingredient are scaled by the same factor.
+fn migrate_ingredient_units() {
+    // Read the stored layout directly to find the factor each ingredient is scaled by
+    let raw: StableBTreeMap<u64, RawIngredient, Memory> =
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))));
+    let factors: std::collections::HashMap<u64, u64> = raw
+        .iter()
+        .filter(|(_, ingredient)| ingredient.0.first() == Some(&2))
+        .filter_map(|(id, ingredient)| {
+            let legacy = Decode!(&ingredient.0[1..], IngredientV2).ok()?;
+            Some((id, parse_legacy_unit(&legacy.unit).factor()))
+        })
+        .collect();
+
+    let ingredients: Vec<(u64, Ingredient)> =
+        INGREDIENTS.with(|ingredients| ingredients.borrow().iter().collect());
+    INGREDIENTS.with(|store| {
+        let mut store = store.borrow_mut();
+        for (id, ingredient) in ingredients {
+            store.insert(id, ingredient);
+        }
+    });
+
+    let recipes: Vec<(u64, Recipe)> = RECIPES.with(|recipes| recipes.borrow().iter().collect());
+    RECIPES.with(|store| {
+        let mut store = store.borrow_mut();
+        for (product_id, mut recipe) in recipes {
+            for line in recipe.lines.iter_mut() {
+                let factor = factors.get(&line.ingredient_id).copied().unwrap_or(1);
+                line.quantity = line.quantity.saturating_mul(factor);
+            }
+            store.insert(product_id, recipe);
+        }
+    });
+}
+
+// Function to record that the stored records use the current layout
+fn set_stored_schema_version() {
+    STORED_SCHEMA_VERSION
+        .with(|cell| cell.borrow_mut().set(SCHEMA_VERSION))
+        .expect("Cannot store the schema version");
+}
+
+// Query function to get the schema version of the stored records
+#[ic_cdk::query]
+fn get_schema_version() -> u8 {
+    STORED_SCHEMA_VERSION.with(|cell| *cell.borrow().get())
+}
+
+// Init hook: the deploying principal becomes the owner
+#[ic_cdk::init]
+fn init() {
+    ROLES.with(|roles| {
+        roles
+            .borrow_mut()
+            .insert(StorablePrincipal(caller()), Role::Owner)
+    });
+    set_stored_schema_version();
+    seed_categories();
+    start_timers();
+}
+
+// Upgrade hook: stored products are migrated to the current schema first.
+// Products stored before the category or search index existed are indexed here.
+// Canisters deployed before roles existed get the upgrading principal as owner.
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrate_products();
+
+    if owner_count() == 0 {
+        ROLES.with(|roles| {
+            roles
+                .borrow_mut()
+                .insert(StorablePrincipal(caller()), Role::Owner)
+        });
+    }
+    start_timers();
+    reconcile_batches();
+
+    let indexed = CATEGORY_INDEX.with(|index| index.borrow().len());
+    let stored = STORAGE.with(|service| service.borrow().len());
+    if indexed != stored {
+        rebuild_category_index();
+    }
+    let searchable = SEARCH_INDEX.with(|index| !index.borrow().is_empty());
+    if !searchable && stored > 0 {
+        rebuild_search_index();
+    }
+}
+
+// Custom error handling enum
+#[derive(candid::CandidType, Deserialize, Serialize)]
+enum Error {
+    NotFound { msg: String },
+    InvalidOperation { msg: String },
+    Unauthorized { msg: String },
+    IncompatibleUnit { msg: String },
+    Conflict { msg: String, current_version: u64 },
+}
+
+impl Error {
+    // HTTP status code reported for the error by http_request
+    fn status_code(&self) -> u16 {
+        match self {
+            Error::NotFound { .. } => 404,
+            Error::InvalidOperation { .. } | Error::IncompatibleUnit { .. } => 400,
+            Error::Unauthorized { .. } => 403,
+            Error::Conflict { .. } => 409,
+        }
+    }
+
+    // Human readable description of the error
+    fn message(&self) -> &str {
+        match self {
+            Error::NotFound { msg }
+            | Error::InvalidOperation { msg }
+            | Error::Unauthorized { msg }
+            | Error::IncompatibleUnit { msg }
+            | Error::Conflict { msg, .. } => msg,
+        }
+    }
 }
 
 // Export candid interface
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_product() -> Product {
+        Product {
+            id: 7,
+            name: "Croissant, \"butter\"".to_string(),
+            category_id: 1,
+            quantity: 0,
+            unit: Unit::Piece,
+            price: 250,
+            unit_cost: 90,
+            currency: "USD".to_string(),
+            reorder_point: Some(12),
+            reorder_quantity: 48,
+            created_at: 1,
+            updated_at: Some(2),
+            archived_at: None,
+            version: 3,
+        }
+    }
+
+    fn fields(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_csv_keeps_commas_inside_quotes() {
+        let records = parse_csv("a,\"b,c\",d").ok().unwrap();
+        assert_eq!(records, vec![(1, fields(&["a", "b,c", "d"]))]);
+    }
+
+    #[test]
+    fn parse_csv_unescapes_doubled_quotes() {
+        let records = parse_csv("\"say \"\"hi\"\"\",x").ok().unwrap();
+        assert_eq!(records, vec![(1, fields(&["say \"hi\"", "x"]))]);
+    }
+
+    #[test]
+    fn parse_csv_splits_crlf_lines_and_skips_blank_ones() {
+        let records = parse_csv("a,b\r\n\r\nc,d\r\n").ok().unwrap();
+        assert_eq!(
+            records,
+            vec![(1, fields(&["a", "b"])), (3, fields(&["c", "d"]))]
+        );
+    }
+
+    #[test]
+    fn parse_csv_keeps_line_breaks_inside_quotes() {
+        let records = parse_csv("\"first\nsecond\",x\ny,z").ok().unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (1, fields(&["first\nsecond", "x"])),
+                (3, fields(&["y", "z"]))
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_csv_rejects_an_unterminated_quote() {
+        assert!(parse_csv("a,b\n\"c,d\n").is_err());
+    }
+
+    #[test]
+    fn exported_product_reads_back_unchanged() {
+        let product = sample_product();
+        let mut csv = String::new();
+        push_csv_row(&mut csv, &PRODUCT_CSV_COLUMNS.map(String::from));
+        push_csv_row(&mut csv, &product_csv_fields(&product));
+
+        let records = parse_csv(&csv).ok().unwrap();
+        let columns: BTreeMap<String, usize> = records[0]
+            .1
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (name.clone(), index))
+            .collect();
+        let row = &records[1].1;
+        assert_eq!(csv_row_id(&columns, row).ok().unwrap(), Some(product.id));
+
+        let (payload, reorder) = product_from_csv_row(&columns, row, Some(&product))
+            .ok()
+            .unwrap();
+        assert_eq!(payload.name, product.name);
+        assert_eq!(payload.category_id, product.category_id);
+        assert_eq!(payload.quantity, 0);
+        assert_eq!(payload.unit, product.unit);
+        assert_eq!(payload.price, product.price);
+        assert_eq!(payload.unit_cost, product.unit_cost);
+        assert_eq!(payload.currency, product.currency);
+        assert_eq!(reorder.reorder_point, product.reorder_point);
+        assert_eq!(reorder.reorder_quantity, product.reorder_quantity);
+        assert!(validate_reorder_payload(&reorder).is_ok());
+    }
+
+    #[test]
+    fn to_base_price_divides_by_the_unit_factor() {
+        assert_eq!(to_base_price(1200, Unit::Dozen).ok(), Some(100));
+        assert_eq!(to_base_price(500, Unit::Gram).ok(), Some(500));
+        assert!(to_base_price(1250, Unit::Dozen).is_err());
+        assert!(to_base_price(499, Unit::Kilogram).is_err());
+    }
+
+    #[test]
+    fn longest_ingredient_name_fits_in_storage() {
+        let ingredient = Ingredient {
+            id: u64::MAX,
+            name: "x".repeat(MAX_INGREDIENT_NAME_LENGTH),
+            unit: Unit::Millilitre,
+            quantity: u64::MAX,
+            created_at: u64::MAX,
+            updated_at: Some(u64::MAX),
+        };
+        assert!(ingredient.to_bytes().len() <= Ingredient::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn longest_product_name_fits_in_storage() {
+        let product = Product {
+            id: u64::MAX,
+            name: "x".repeat(MAX_PRODUCT_NAME_LENGTH),
+            category_id: u64::MAX,
+            quantity: u32::MAX,
+            unit: Unit::Millilitre,
+            price: u64::MAX,
+            unit_cost: u64::MAX,
+            currency: "USD".to_string(),
+            reorder_point: Some(u32::MAX),
+            reorder_quantity: u32::MAX,
+            created_at: u64::MAX,
+            updated_at: Some(u64::MAX),
+            archived_at: Some(u64::MAX),
+            version: u64::MAX,
+        };
+        assert!(product.to_bytes().len() <= Product::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn largest_supplier_fits_in_storage() {
+        let supplier = Supplier {
+            id: u64::MAX,
+            name: "x".repeat(MAX_SUPPLIER_NAME_LENGTH),
+            contact: "x".repeat(MAX_CONTACT_LENGTH),
+            lead_time_days: MAX_LEAD_TIME_DAYS,
+            items: (0..MAX_SUPPLIED_ITEMS as u64)
+                .map(|index| SuppliedItem::Ingredient(u64::MAX - index))
+                .collect(),
+            created_at: u64::MAX,
+            updated_at: Some(u64::MAX),
+        };
+        assert!(supplier.to_bytes().len() <= Supplier::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn snapshot_has_every_section() {
+        let names: Vec<String> = take_snapshot()
+            .sections
+            .into_iter()
+            .map(|section| section.name)
+            .collect();
+        assert_eq!(names, SNAPSHOT_SECTIONS.map(String::from));
+    }
+
+    // The product every old layout below decodes into, before the fields its
+    // layout lacks are filled in with their migration defaults
+    fn migrated_product() -> Product {
+        Product {
+            id: 3,
+            name: "Rye loaf".to_string(),
+            category_id: 1,
+            quantity: 40,
+            unit: Unit::Piece,
+            price: 0,
+            unit_cost: 0,
+            currency: "USD".to_string(),
+            reorder_point: None,
+            reorder_quantity: 0,
+            created_at: 100,
+            updated_at: Some(200),
+            archived_at: None,
+            version: 1,
+        }
+    }
+
+    fn v2() -> ProductV2 {
+        ProductV2 {
+            id: 3,
+            name: "Rye loaf".to_string(),
+            category: LegacyCategory::Cake,
+            quantity: 40,
+            price: 350,
+            unit_cost: 120,
+            currency: "EUR".to_string(),
+            created_at: 100,
+            updated_at: Some(200),
+        }
+    }
+
+    fn v4() -> ProductV4 {
+        ProductV4 {
+            id: 3,
+            name: "Rye loaf".to_string(),
+            category: LegacyCategory::Cake,
+            quantity: 4000,
+            unit: Unit::Gram,
+            price: 350,
+            unit_cost: 120,
+            currency: "EUR".to_string(),
+            reorder_point: Some(500),
+            reorder_quantity: 2000,
+            created_at: 100,
+            updated_at: Some(200),
+        }
+    }
+
+    fn v6() -> ProductV6 {
+        let product = v4();
+        ProductV6 {
+            id: product.id,
+            name: product.name,
+            category_id: 1,
+            quantity: product.quantity,
+            unit: product.unit,
+            price: product.price,
+            unit_cost: product.unit_cost,
+            currency: product.currency,
+            reorder_point: product.reorder_point,
+            reorder_quantity: product.reorder_quantity,
+            created_at: product.created_at,
+            updated_at: product.updated_at,
+            archived_at: Some(300),
+        }
+    }
+
+    // Decodes a stored record and checks it matches the expected product and
+    // is written back in the current layout
+    fn assert_decodes(version: u8, bytes: &[u8], expected: Product) {
+        let product = decode_product(version, bytes).ok().unwrap();
+        let stored = product.to_bytes();
+        assert_eq!(stored[0], SCHEMA_VERSION);
+        assert_eq!(stored, expected.to_bytes());
+    }
+
+    #[test]
+    fn decode_product_migrates_version_1() {
+        let product = ProductV1 {
+            id: 3,
+            name: "Rye loaf".to_string(),
+            category: LegacyCategory::Cake,
+            quantity: 40,
+            created_at: 100,
+            updated_at: Some(200),
+        };
+        assert_decodes(1, &Encode!(&product).unwrap(), migrated_product());
+    }
+
+    #[test]
+    fn decode_product_migrates_version_2() {
+        let expected = Product {
+            price: 350,
+            unit_cost: 120,
+            currency: "EUR".to_string(),
+            ..migrated_product()
+        };
+        assert_decodes(2, &Encode!(&v2()).unwrap(), expected);
+    }
+
+    #[test]
+    fn decode_product_migrates_version_3() {
+        let product = v2();
+        let product = ProductV3 {
+            id: product.id,
+            name: product.name,
+            category: product.category,
+            quantity: 4000,
+            unit: Unit::Gram,
+            price: product.price,
+            unit_cost: product.unit_cost,
+            currency: product.currency,
+            created_at: product.created_at,
+            updated_at: product.updated_at,
+        };
+        let expected = Product {
+            quantity: 4000,
+            unit: Unit::Gram,
+            price: 350,
+            unit_cost: 120,
+            currency: "EUR".to_string(),
+            ..migrated_product()
+        };
+        assert_decodes(3, &Encode!(&product).unwrap(), expected);
+    }
+
+    #[test]
+    fn decode_product_migrates_version_4() {
+        let expected = Product {
+            quantity: 4000,
+            unit: Unit::Gram,
+            price: 350,
+            unit_cost: 120,
+            currency: "EUR".to_string(),
+            reorder_point: Some(500),
+            reorder_quantity: 2000,
+            ..migrated_product()
+        };
+        assert_decodes(4, &Encode!(&v4()).unwrap(), expected);
+    }
+
+    #[test]
+    fn decode_product_migrates_version_5() {
+        let product = v6();
+        let product = ProductV5 {
+            id: product.id,
+            name: product.name,
+            category_id: product.category_id,
+            quantity: product.quantity,
+            unit: product.unit,
+            price: product.price,
+            unit_cost: product.unit_cost,
+            currency: product.currency,
+            reorder_point: product.reorder_point,
+            reorder_quantity: product.reorder_quantity,
+            created_at: product.created_at,
+            updated_at: product.updated_at,
+        };
+        let expected = Product {
+            quantity: 4000,
+            unit: Unit::Gram,
+            price: 350,
+            unit_cost: 120,
+            currency: "EUR".to_string(),
+            reorder_point: Some(500),
+            reorder_quantity: 2000,
+            ..migrated_product()
+        };
+        assert_decodes(5, &Encode!(&product).unwrap(), expected);
+    }
+
+    #[test]
+    fn decode_product_migrates_version_6() {
+        let expected = Product {
+            quantity: 4000,
+            unit: Unit::Gram,
+            price: 350,
+            unit_cost: 120,
+            currency: "EUR".to_string(),
+            reorder_point: Some(500),
+            reorder_quantity: 2000,
+            archived_at: Some(300),
+            ..migrated_product()
+        };
+        assert_decodes(6, &Encode!(&v6()).unwrap(), expected);
+    }
+
+    #[test]
+    fn decode_product_reads_the_current_layout() {
+        let product = sample_product();
+        for version in [7, SCHEMA_VERSION] {
+            assert_decodes(version, &Encode!(&product).unwrap(), product.clone());
+        }
+        assert!(decode_product(SCHEMA_VERSION + 1, &Encode!(&product).unwrap()).is_err());
+    }
+
+    #[test]
+    fn unversioned_products_decode_as_version_2_or_1() {
+        let product = Product::from_bytes(Cow::Owned(Encode!(&v2()).unwrap()));
+        assert_eq!(product.price, 350);
+        assert_eq!(product.category_id, 1);
+        assert_eq!(product.to_bytes()[0], SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn tokenize_lowercases_and_deduplicates_words() {
+        assert_eq!(
+            tokenize("Rye-Bread, rye BREAD 2go!"),
+            vec!["rye", "bread", "2go"]
+        );
+        assert!(tokenize(" -- ").is_empty());
+    }
+
+    #[test]
+    fn tokenize_cuts_long_words_on_a_character_boundary() {
+        let tokens = tokenize(&"é".repeat(MAX_TOKEN_LENGTH));
+        assert_eq!(tokens, vec!["é".repeat(MAX_TOKEN_LENGTH / 2)]);
+        let tokens = tokenize(&format!("a{}", "é".repeat(MAX_TOKEN_LENGTH)));
+        assert_eq!(tokens[0].len(), MAX_TOKEN_LENGTH - 1);
+    }
+
+    #[test]
+    fn to_base_quantity_multiplies_by_the_unit_factor() {
+        assert_eq!(to_base_quantity(2, Unit::Dozen, Unit::Piece).ok(), Some(24));
+        assert_eq!(
+            to_base_quantity(3, Unit::Kilogram, Unit::Gram).ok(),
+            Some(3000)
+        );
+        assert_eq!(
+            to_base_quantity(5, Unit::Millilitre, Unit::Litre).ok(),
+            Some(5)
+        );
+        assert!(to_base_quantity(1, Unit::Litre, Unit::Gram).is_err());
+        assert!(to_base_quantity(u64::MAX, Unit::Kilogram, Unit::Gram).is_err());
+    }
+
+    #[test]
+    fn audit_summary_keeps_short_summaries_whole() {
+        assert_eq!(audit_summary(&vec![1, 2]), "[1,2]");
+    }
+
+    #[test]
+    fn audit_summary_truncates_on_a_character_boundary() {
+        let summary = audit_summary(&"é".repeat(MAX_AUDIT_SUMMARY_LENGTH));
+        assert!(summary.len() <= MAX_AUDIT_SUMMARY_LENGTH);
+        assert!(summary.ends_with(AUDIT_TRUNCATED_MARKER));
+        assert!(summary.starts_with("\"é"));
+    }
+
+    #[test]
+    fn csv_row_with_a_bad_number_fails() {
+        let columns: BTreeMap<String, usize> = PRODUCT_CSV_COLUMNS
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (name.to_string(), index))
+            .collect();
+        let mut row = product_csv_fields(&sample_product()).to_vec();
+        row[9] = "lots".to_string();
+        assert!(product_from_csv_row(&columns, &row, Some(&sample_product())).is_err());
+        row[9] = "48".to_string();
+        row[3] = "-1".to_string();
+        assert!(product_from_csv_row(&columns, &row, Some(&sample_product())).is_err());
+    }
+}